@@ -0,0 +1,192 @@
+//! Configuration helpers shared by the delivery and payment customization functions.
+
+use std::borrow::Cow;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize};
+
+pub mod logging;
+pub mod money;
+pub mod query;
+
+/// Parse the JSON value of a function owner's configuration metafield. A value that was encoded
+/// twice, arriving as a JSON string holding the configuration, is decoded once more.
+pub fn parse_config<T: DeserializeOwned>(value: &str) -> Result<T, serde_json::Error> {
+    if value.trim_start().starts_with('"') {
+        let decoded: String = serde_json::from_str(value)?;
+        return serde_json::from_str(&decoded);
+    }
+    serde_json::from_str(value)
+}
+
+/// The `version` of a parsed configuration, or 1 for configurations written before it existed.
+pub fn config_version(value: &serde_json::Value) -> u64 {
+    value.get("version").and_then(serde_json::Value::as_u64).unwrap_or(1)
+}
+
+/// Rename keys in a parsed configuration and in each of its `rules`, for migrating an older
+/// version. A key is left alone when the configuration already uses its new name.
+pub fn rename_rule_keys(value: &mut serde_json::Value, renames: &[(&str, &str)]) {
+    let mut rename = |object: &mut serde_json::Map<String, serde_json::Value>| {
+        for (from, to) in renames {
+            if !object.contains_key(*to) {
+                if let Some(moved) = object.remove(*from) {
+                    object.insert(to.to_string(), moved);
+                }
+            }
+        }
+    };
+    if let Some(object) = value.as_object_mut() {
+        rename(object);
+        if let Some(rules) = object.get_mut("rules").and_then(serde_json::Value::as_array_mut) {
+            rules.iter_mut().filter_map(serde_json::Value::as_object_mut).for_each(&mut rename);
+        }
+    }
+}
+
+/// Deserialize either a single string or a list of strings into a collection such as a `Vec` or a
+/// `HashSet`, normalizing their whitespace and dropping the values left empty.
+pub fn deserialize_one_or_many<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromIterator<String>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    let values = match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    };
+    Ok(values
+        .iter()
+        .map(|value| normalize_whitespace(value).into_owned())
+        .filter(|value| !value.is_empty())
+        .collect())
+}
+
+/// Trim a value and collapse each run of whitespace inside it, newlines included, into a single
+/// space, so that values pasted with stray spaces still compare equal. Values that are already
+/// normalized are borrowed as they are.
+pub fn normalize_whitespace(value: &str) -> Cow<'_, str> {
+    let is_normalized = value.is_empty()
+        || value.split(' ').all(|word| !word.is_empty() && !word.contains(char::is_whitespace));
+    if is_normalized {
+        Cow::Borrowed(value)
+    } else {
+        Cow::Owned(value.split_whitespace().collect::<Vec<_>>().join(" "))
+    }
+}
+
+/// Strip the accents and every character other than letters and digits from a name, so that names
+/// written differently by different gateways, like "Carte Bancaire" and "carte-bancaire", compare
+/// equal once their case is ignored. Only Latin letters are folded, which covers payment method names
+/// in practice without pulling in a Unicode normalization table. Letters of other scripts are kept.
+pub fn normalize_name(value: &str) -> String {
+    let mut normalized = String::with_capacity(value.len());
+    for c in value.chars() {
+        match NAME_FOLDS.iter().find(|(accented, _)| accented.contains(c)) {
+            Some((_, folded)) => normalized.push_str(folded),
+            // Excluding the combining accents of decomposed names
+            None if c.is_alphanumeric() && !('\u{300}'..='\u{36f}').contains(&c) => normalized.push(c),
+            None => {}
+        }
+    }
+    normalized
+}
+
+const NAME_FOLDS: &[(&str, &str)] = &[
+    ("ÀÁÂÃÄÅĀĂĄ", "A"), ("àáâãäåāăą", "a"), ("ÇĆĈĊČ", "C"), ("çćĉċč", "c"), ("ĎĐ", "D"), ("ďđ", "d"),
+    ("ÈÉÊËĒĔĖĘĚ", "E"), ("èéêëēĕėęě", "e"), ("ĜĞĠĢ", "G"), ("ĝğġģ", "g"), ("ĤĦ", "H"), ("ĥħ", "h"),
+    ("ÌÍÎÏĨĪĬĮİ", "I"), ("ìíîïĩīĭįı", "i"), ("Ĵ", "J"), ("ĵ", "j"), ("Ķ", "K"), ("ķ", "k"),
+    ("ĹĻĽĿŁ", "L"), ("ĺļľŀł", "l"), ("ÑŃŅŇ", "N"), ("ñńņň", "n"), ("ÒÓÔÕÖØŌŎŐ", "O"), ("òóôõöøōŏő", "o"),
+    ("ŔŖŘ", "R"), ("ŕŗř", "r"), ("ŚŜŞŠ", "S"), ("śŝşš", "s"), ("ŢŤŦ", "T"), ("ţťŧ", "t"),
+    ("ÙÚÛÜŨŪŬŮŰŲ", "U"), ("ùúûüũūŭůűų", "u"), ("Ŵ", "W"), ("ŵ", "w"), ("ÝŶŸ", "Y"), ("ýÿŷ", "y"),
+    ("ŹŻŽ", "Z"), ("źżž", "z"), ("Æ", "AE"), ("æ", "ae"), ("Œ", "OE"), ("œ", "oe"), ("ß", "ss"),
+];
+
+/// The string value of a generated GraphQL enum, which is only exposed through serde.
+pub fn enum_value<T: Serialize>(value: &T) -> Option<String> {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::String(value)) => Some(value),
+        _ => None,
+    }
+}
+
+/// Whether a message goes before or after the original title.
+#[cfg(feature = "delivery")]
+#[derive(Serialize, Deserialize, Default, PartialEq, Eq, Clone, Copy, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum MessagePosition {
+    Prefix,
+    #[default]
+    Suffix,
+}
+
+/// Where a move operation puts an option, configured as `"top"`, `"bottom"` or `{"index": 2}`.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum MoveTarget {
+    Top,
+    Bottom,
+    /// Shopify indexes options from the top of the list, so 0 is first. Negative indices are
+    /// clamped to 0.
+    Index(i32),
+}
+
+impl MoveTarget {
+    /// The index to send to Shopify for a list of `len` options. With N options, `Bottom` is index
+    /// N - 1, and 0 for an empty list.
+    pub fn index(self, len: usize) -> i64 {
+        match self {
+            MoveTarget::Top => 0,
+            MoveTarget::Bottom => i64::try_from(len.saturating_sub(1)).unwrap_or(i64::MAX),
+            MoveTarget::Index(index) => i64::from(index.max(0)),
+        }
+    }
+}
+
+/// An action a function's operations can take, named like the fields of an output operation.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum OperationKind {
+    Hide,
+    Move,
+    Rename,
+}
+
+impl OperationKind {
+    /// Every kind, which is what a configuration supports unless it lists fewer.
+    pub const ALL: [OperationKind; 3] = [OperationKind::Hide, OperationKind::Move, OperationKind::Rename];
+
+    /// The kind's name, as in a configuration's `supportedOperations`.
+    pub fn name(self) -> &'static str {
+        match self {
+            OperationKind::Hide => "hide",
+            OperationKind::Move => "move",
+            OperationKind::Rename => "rename",
+        }
+    }
+}
+
+/// Sort operations into a stable order: renames, then hides, each by the handle or id they target, then
+/// moves in the order they were produced, since where a move puts its target depends on the moves before
+/// it. `key` gives an operation's kind and target, and operations without one go last.
+pub fn sort_operations<T>(operations: &mut [T], key: impl for<'a> Fn(&'a T) -> Option<(OperationKind, &'a str)>) {
+    let order = |operation: &T| match key(operation) {
+        Some((OperationKind::Rename, target)) => (0, target.to_string()),
+        Some((OperationKind::Hide, target)) => (1, target.to_string()),
+        Some((OperationKind::Move, _)) => (2, String::new()),
+        None => (3, String::new()),
+    };
+    // Stable, so moves keep the order they were produced in
+    operations.sort_by_cached_key(order);
+}
+
+// The tests cover the types of both extensions, which the default features include
+#[cfg(all(test, feature = "delivery", feature = "payment"))]
+mod tests;
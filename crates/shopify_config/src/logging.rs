@@ -0,0 +1,90 @@
+//! Single-line JSON log entries, which are easier to search in Shopify's function run logs than
+//! free text.
+
+use std::time::Instant;
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::OperationKind;
+
+/// How severe a log entry is.
+#[derive(Serialize, PartialEq, Eq, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+/// Format a log entry as a JSON object with `level`, `message` and the given contextual fields.
+pub fn entry(level: Level, message: &str, fields: &[(&str, Value)]) -> String {
+    let mut object = Map::new();
+    object.insert("level".to_string(), serde_json::json!(level));
+    object.insert("message".to_string(), Value::String(message.to_string()));
+    for (key, value) in fields {
+        object.insert(key.to_string(), value.clone());
+    }
+    Value::Object(object).to_string()
+}
+
+/// Format a metric entry as a JSON object with `metric` naming it and its values, like
+/// `{"metric": "operations", "hide": 1, "move": 0, "rename": 2}`. Dashboards tell metrics apart from log
+/// entries by their `metric` key.
+pub fn metric(name: &str, values: &[(&str, Value)]) -> String {
+    let mut object = Map::new();
+    object.insert("metric".to_string(), Value::String(name.to_string()));
+    for (key, value) in values {
+        object.insert(key.to_string(), value.clone());
+    }
+    Value::Object(object).to_string()
+}
+
+/// Count operations by kind as metric values. Kinds without operations count zero, so every run reports
+/// every kind.
+pub fn operation_counts(kinds: impl IntoIterator<Item = OperationKind>) -> Vec<(&'static str, Value)> {
+    let mut counts = OperationKind::ALL.map(|kind| (kind, 0u64));
+    for kind in kinds {
+        if let Some((_, count)) = counts.iter_mut().find(|(counted, _)| *counted == kind) {
+            *count += 1;
+        }
+    }
+    counts.iter().map(|(kind, count)| (kind.name(), Value::from(*count))).collect()
+}
+
+/// Times consecutive phases of a run, like parsing the configuration and building the operations, to
+/// find which phase dominates for large carts and configurations. A disabled profiler never reads the
+/// clock.
+pub struct Profiler {
+    phase_started: Option<Instant>,
+    phases: Vec<(String, Value)>,
+}
+
+impl Profiler {
+    /// A profiler whose first phase starts now, or one that records nothing when disabled.
+    pub fn start(enabled: bool) -> Self {
+        Profiler { phase_started: enabled.then(Instant::now), phases: vec![] }
+    }
+
+    /// A profiler whose first phase started at `started`, or one that records nothing when disabled.
+    pub fn since(started: Instant, enabled: bool) -> Self {
+        Profiler { phase_started: enabled.then_some(started), phases: vec![] }
+    }
+
+    /// End the current phase, recording its duration in microseconds as `<name>_us`, and start the next.
+    pub fn phase(&mut self, name: &str) {
+        if let Some(phase_started) = self.phase_started {
+            let now = Instant::now();
+            let micros = u64::try_from(now.duration_since(phase_started).as_micros()).unwrap_or(u64::MAX);
+            self.phases.push((format!("{}_us", name), Value::from(micros)));
+            self.phase_started = Some(now);
+        }
+    }
+
+    /// The recorded durations as log entry fields, or `None` when disabled.
+    pub fn fields(&self) -> Option<Vec<(&str, Value)>> {
+        self.phase_started?;
+        Some(self.phases.iter().map(|(name, micros)| (name.as_str(), micros.clone())).collect())
+    }
+}
@@ -0,0 +1,122 @@
+//! Exact money amounts, so comparisons near a threshold aren't skewed by float rounding.
+//!
+//! The generated function types use a `Decimal` string scalar, so extensions refer to this
+//! module's `Decimal` by path to avoid shadowing it.
+
+use std::collections::HashMap;
+
+#[cfg(feature = "payment")]
+use rust_decimal::RoundingStrategy;
+use serde::{Deserialize, Deserializer};
+#[cfg(feature = "payment")]
+use serde::Serialize;
+
+pub use rust_decimal::Decimal;
+
+/// How an amount is rounded to its currency's decimal places before it's compared to a threshold.
+#[cfg(feature = "payment")]
+#[derive(Serialize, Deserialize, Default, PartialEq, Eq, Clone, Copy, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum RoundingMode {
+    /// Midpoints round away from zero, so `0.125` becomes `0.13`.
+    HalfUp,
+    /// Midpoints round to the nearest even digit, so `0.125` becomes `0.12` and `0.135` becomes `0.14`.
+    #[default]
+    HalfEven,
+    /// Extra digits are dropped, so `0.129` becomes `0.12`.
+    Down,
+}
+
+#[cfg(feature = "payment")]
+impl RoundingMode {
+    /// Round an amount to the given number of decimal places.
+    pub fn round(self, amount: Decimal, decimal_places: u32) -> Decimal {
+        let strategy = match self {
+            RoundingMode::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+            RoundingMode::HalfEven => RoundingStrategy::MidpointNearestEven,
+            RoundingMode::Down => RoundingStrategy::ToZero,
+        };
+        amount.round_dp_with_strategy(decimal_places, strategy)
+    }
+}
+
+/// The number of decimal places of an ISO 4217 currency's minor unit, defaulting to two for unknown
+/// currencies.
+#[cfg(feature = "payment")]
+pub fn currency_decimal_places(currency_code: &str) -> u32 {
+    match currency_code {
+        "BIF" | "CLP" | "DJF" | "GNF" | "ISK" | "JPY" | "KMF" | "KRW" | "PYG" | "RWF" | "UGX" | "VND"
+        | "VUV" | "XAF" | "XOF" | "XPF" => 0,
+        "BHD" | "IQD" | "JOD" | "KWD" | "LYD" | "OMR" | "TND" => 3,
+        _ => 2,
+    }
+}
+
+/// Parse a decimal money amount such as `"100.00"`, returning `None` when it isn't numeric.
+pub fn parse_decimal(value: &str) -> Option<Decimal> {
+    let value = value.trim();
+    value
+        .parse::<Decimal>()
+        .or_else(|_| Decimal::from_scientific(value))
+        .ok()
+}
+
+/// Deserialize a money amount from either a JSON number or a decimal string.
+pub fn deserialize_decimal<'de, D>(deserializer: D) -> Result<Decimal, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum NumberOrString {
+        Number(serde_json::Number),
+        String(String),
+    }
+
+    let value = match NumberOrString::deserialize(deserializer)? {
+        NumberOrString::Number(number) => number.to_string(),
+        NumberOrString::String(string) => string,
+    };
+    parse_decimal(&value)
+        .ok_or_else(|| serde::de::Error::custom(format!("\"{}\" is not a valid decimal amount", value)))
+}
+
+/// Deserialize an optional money amount from either a JSON number or a decimal string.
+pub fn deserialize_optional_decimal<'de, D>(deserializer: D) -> Result<Option<Decimal>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct Wrapper(#[serde(deserialize_with = "deserialize_decimal")] Decimal);
+
+    Ok(Option::<Wrapper>::deserialize(deserializer)?.map(|Wrapper(value)| value))
+}
+
+/// Deserialize a map of money amounts, each from either a JSON number or a decimal string.
+pub fn deserialize_decimal_map<'de, D>(deserializer: D) -> Result<HashMap<String, Decimal>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct Wrapper(#[serde(deserialize_with = "deserialize_decimal")] Decimal);
+
+    Ok(HashMap::<String, Wrapper>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(key, Wrapper(value))| (key, value))
+        .collect())
+}
+
+/// Deserialize pairs of a money amount, from either a JSON number or a decimal string, and a value.
+pub fn deserialize_decimal_tiers<'de, D, T>(deserializer: D) -> Result<Vec<(Decimal, T)>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    struct Wrapper(#[serde(deserialize_with = "deserialize_decimal")] Decimal);
+
+    Ok(Vec::<(Wrapper, T)>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(Wrapper(amount), value)| (amount, value))
+        .collect())
+}
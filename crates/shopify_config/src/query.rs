@@ -0,0 +1,33 @@
+//! Rewriting of function input queries, for build scripts that tailor the query a function is
+//! deployed with.
+
+/// Point the first `metafield(namespace: ..., key: ...)` selection of an input query at another
+/// namespace and/or key, leaving the rest of the query as it is.
+pub fn target_metafield(query: &str, namespace: Option<&str>, key: Option<&str>) -> String {
+    let Some(start) = query.find("metafield(") else {
+        return query.to_string();
+    };
+    let Some(end) = query[start..].find(')').map(|end| start + end) else {
+        return query.to_string();
+    };
+    let mut arguments = query[start..end].to_string();
+    for (name, value) in [("namespace", namespace), ("key", key)] {
+        if let Some(value) = value {
+            arguments = replace_argument(&arguments, name, value);
+        }
+    }
+    format!("{}{}{}", &query[..start], arguments, &query[end..])
+}
+
+// Replace the quoted value of a `name: "value"` argument
+fn replace_argument(arguments: &str, name: &str, value: &str) -> String {
+    let quoted = arguments.find(&format!("{}:", name)).and_then(|position| {
+        let open = position + arguments[position..].find('"')?;
+        let close = open + 1 + arguments[open + 1..].find('"')?;
+        Some((open, close))
+    });
+    match quoted {
+        Some((open, close)) => format!("{}\"{}\"{}", &arguments[..open], value, &arguments[close + 1..]),
+        None => arguments.to_string(),
+    }
+}
@@ -0,0 +1,226 @@
+use super::logging::{self, Level};
+use super::money::{self, Decimal};
+use super::query;
+use super::*;
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct Example {
+    #[serde(default, deserialize_with = "deserialize_one_or_many")]
+    names: Vec<String>,
+    #[serde(deserialize_with = "money::deserialize_decimal")]
+    amount: Decimal,
+    #[serde(default)]
+    message_position: MessagePosition,
+}
+
+#[test]
+fn test_parse_config_success() {
+    let config: Example =
+        parse_config(r#"{"names": ["a", "", "b"], "amount": "10.50", "messagePosition": "prefix"}"#)
+            .unwrap();
+
+    assert_eq!(
+        config,
+        Example {
+            names: vec!["a".to_string(), "b".to_string()],
+            amount: Decimal::new(1050, 2),
+            message_position: MessagePosition::Prefix,
+        }
+    );
+}
+
+#[test]
+fn test_parse_config_accepts_a_single_string_and_a_numeric_amount() {
+    let config: Example = parse_config(r#"{"names": "a", "amount": 10.5}"#).unwrap();
+
+    assert_eq!(config.names, vec!["a".to_string()]);
+    assert_eq!(config.amount, Decimal::new(105, 1));
+    assert_eq!(config.message_position, MessagePosition::Suffix);
+}
+
+#[test]
+fn test_parse_config_error() {
+    assert!(parse_config::<Example>(r#"{"names": ["a"]"#).is_err());
+    assert!(parse_config::<Example>(r#"{"names": ["a"]}"#).is_err());
+
+    let error = parse_config::<Example>(r#"{"amount": "abc"}"#).unwrap_err();
+    assert!(error.to_string().contains("\"abc\" is not a valid decimal amount"));
+}
+
+#[test]
+fn test_parse_decimal() {
+    assert_eq!(money::parse_decimal(" 99.99 "), Some(Decimal::new(9999, 2)));
+    assert_eq!(money::parse_decimal("1e2"), Some(Decimal::new(100, 0)));
+    assert_eq!(money::parse_decimal("not-a-number"), None);
+}
+
+#[test]
+fn test_enum_value() {
+    assert_eq!(enum_value(&MessagePosition::Prefix), Some("prefix".to_string()));
+    assert_eq!(enum_value(&42), None);
+}
+
+#[test]
+fn test_log_entry_is_single_line_json() {
+    let entry = logging::entry(
+        Level::Info,
+        "Cart total is not high enough",
+        &[("cart_total", serde_json::json!("99.99")), ("threshold", serde_json::json!(100))],
+    );
+    let parsed: serde_json::Value = serde_json::from_str(&entry).unwrap();
+
+    assert!(!entry.contains('\n'));
+    assert_eq!(
+        parsed,
+        serde_json::json!({
+            "level": "info",
+            "message": "Cart total is not high enough",
+            "cart_total": "99.99",
+            "threshold": 100
+        })
+    );
+}
+
+#[test]
+fn test_config_version_defaults_to_one() {
+    assert_eq!(config_version(&serde_json::json!({"zip": "90210"})), 1);
+    assert_eq!(config_version(&serde_json::json!({"version": 2})), 2);
+}
+
+#[test]
+fn test_rename_rule_keys_renames_top_level_and_rule_keys() {
+    let mut value = serde_json::json!({
+        "zip": "90210",
+        "rules": [{"zip": "10001"}, {"zip": "60601", "zips": ["60602"]}]
+    });
+
+    rename_rule_keys(&mut value, &[("zip", "zips")]);
+
+    assert_eq!(
+        value,
+        serde_json::json!({
+            "zips": "90210",
+            "rules": [{"zips": "10001"}, {"zip": "60601", "zips": ["60602"]}]
+        })
+    );
+}
+
+#[test]
+fn test_move_target_index() {
+    assert_eq!(MoveTarget::Top.index(4), 0);
+    assert_eq!(MoveTarget::Bottom.index(4), 3);
+    assert_eq!(MoveTarget::Bottom.index(0), 0);
+    assert_eq!(MoveTarget::Index(2).index(4), 2);
+    assert_eq!(MoveTarget::Index(-1).index(4), 0);
+}
+
+#[test]
+fn test_move_target_deserializes() {
+    let targets: Vec<MoveTarget> = serde_json::from_str(r#"["top", "bottom", {"index": 2}]"#).unwrap();
+
+    assert_eq!(targets, vec![MoveTarget::Top, MoveTarget::Bottom, MoveTarget::Index(2)]);
+}
+
+#[test]
+fn test_parse_config_decodes_doubly_encoded_values() {
+    let value = r#"{"names": "a", "amount": 1}"#;
+    let encoded = serde_json::to_string(value).unwrap();
+
+    assert_eq!(parse_config::<Example>(&encoded).unwrap(), parse_config::<Example>(value).unwrap());
+}
+
+#[test]
+fn test_target_metafield_replaces_the_configured_arguments() {
+    let query = "{\n  owner {\n    metafield(\n      namespace: \"$app:owner\",\n      key: \"function-configuration\"\n    ) {\n      value\n    }\n  }\n}";
+
+    assert_eq!(
+        query::target_metafield(query, None, Some("staging-configuration")),
+        query.replace("function-configuration", "staging-configuration")
+    );
+    assert_eq!(
+        query::target_metafield(query, Some("$app:staging"), Some("staging-configuration")),
+        query.replace("$app:owner", "$app:staging").replace("function-configuration", "staging-configuration")
+    );
+    assert_eq!(query::target_metafield(query, None, None), query);
+}
+
+#[test]
+fn test_normalize_whitespace() {
+    assert!(matches!(normalize_whitespace("E1 6AN"), Cow::Borrowed("E1 6AN")));
+    assert_eq!(normalize_whitespace("  E1 \t 6AN\n"), "E1 6AN");
+    assert_eq!(normalize_whitespace(" \n "), "");
+    assert_eq!(normalize_whitespace(""), "");
+}
+
+#[test]
+fn test_parse_config_normalizes_names() {
+    let config: Example = parse_config(r#"{"names": [" a ", "b\n", "  ", "c  d"], "amount": 1}"#).unwrap();
+
+    assert_eq!(config.names, vec!["a".to_string(), "b".to_string(), "c d".to_string()]);
+}
+
+#[test]
+fn test_rounding_modes_at_the_midpoint() {
+    let amount = Decimal::new(99985, 3);
+
+    assert_eq!(money::RoundingMode::HalfUp.round(amount, 2), Decimal::new(9999, 2));
+    assert_eq!(money::RoundingMode::HalfEven.round(amount, 2), Decimal::new(9998, 2));
+    assert_eq!(money::RoundingMode::Down.round(Decimal::new(99989, 3), 2), Decimal::new(9998, 2));
+    assert_eq!(money::currency_decimal_places("KWD"), 3);
+    assert_eq!(money::currency_decimal_places("JPY"), 0);
+    assert_eq!(money::currency_decimal_places("USD"), 2);
+}
+
+#[test]
+fn test_profiler_records_phases_only_when_enabled() {
+    let mut disabled = logging::Profiler::start(false);
+    disabled.phase("parse");
+    assert_eq!(disabled.fields(), None);
+
+    let mut enabled = logging::Profiler::start(true);
+    enabled.phase("parse");
+    enabled.phase("build");
+    let names: Vec<_> = enabled.fields().unwrap().into_iter().map(|(name, _)| name).collect();
+    assert_eq!(names, vec!["parse_us", "build_us"]);
+}
+
+#[test]
+fn test_operation_counts_include_every_kind() {
+    let counts = logging::operation_counts([OperationKind::Rename, OperationKind::Hide, OperationKind::Rename]);
+    let metric: serde_json::Value = serde_json::from_str(&logging::metric("operations", &counts)).unwrap();
+
+    assert_eq!(metric, serde_json::json!({"metric": "operations", "hide": 1, "move": 0, "rename": 2}));
+}
+
+#[test]
+fn test_sort_operations_orders_renames_and_hides_by_target_and_keeps_moves_in_order() {
+    let mut operations = vec![
+        (OperationKind::Move, "b"),
+        (OperationKind::Hide, "c"),
+        (OperationKind::Rename, "b"),
+        (OperationKind::Move, "a"),
+        (OperationKind::Hide, "a"),
+        (OperationKind::Rename, "a"),
+    ];
+    sort_operations(&mut operations, |&(kind, target)| Some((kind, target)));
+
+    assert_eq!(operations, vec![
+        (OperationKind::Rename, "a"),
+        (OperationKind::Rename, "b"),
+        (OperationKind::Hide, "a"),
+        (OperationKind::Hide, "c"),
+        (OperationKind::Move, "b"),
+        (OperationKind::Move, "a"),
+    ]);
+}
+
+#[test]
+fn test_normalize_name_strips_accents_and_punctuation() {
+    assert_eq!(normalize_name("Carte-Bancaire"), "CarteBancaire");
+    assert_eq!(normalize_name(" Paiement à la livraison! "), "Paiementalalivraison");
+    assert_eq!(normalize_name("Überweisung (Straße)"), "UberweisungStrasse");
+    // "é" decomposed into "e" and a combining acute accent
+    assert_eq!(normalize_name("Espe\u{301}ces"), "Especes");
+    assert_eq!(normalize_name("代金引換 / Cash"), "代金引換Cash");
+}
@@ -0,0 +1,46 @@
+//! Each extension enables only its own features of this crate, so it builds and tests on its own with
+//! `cargo test -p <extension>` without compiling the other extension's configuration types.
+
+use std::path::Path;
+use std::process::Command;
+
+const EXTENSIONS: [(&str, &str); 2] = [("delivery-customization", "delivery"), ("payment-customization", "payment")];
+
+fn cargo(args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO"))
+        .args(args)
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .expect("cargo should run");
+    assert!(output.status.success(), "cargo {} failed:\n{}", args.join(" "), String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).expect("cargo output should be UTF-8")
+}
+
+/// The features of this crate that cargo resolves when building `package` on its own.
+fn shared_features(package: &str) -> Vec<String> {
+    let tree = cargo(&["tree", "--package", package, "--edges", "normal", "--prefix", "none", "--format", "{p}|{f}"]);
+    let line = tree
+        .lines()
+        .find(|line| line.starts_with("shopify_config "))
+        .unwrap_or_else(|| panic!("{} should depend on shopify_config", package));
+    let (_, features) = line.split_once('|').expect("the format separates features with |");
+    features.split(',').filter(|feature| !feature.is_empty()).map(str::to_string).collect()
+}
+
+#[test]
+fn test_each_extension_only_enables_its_own_features() {
+    for (package, feature) in EXTENSIONS {
+        assert_eq!(shared_features(package), vec![feature.to_string()], "features enabled by {}", package);
+    }
+}
+
+// Slow, since it builds each extension from scratch in its own target directory
+#[test]
+#[ignore = "builds each extension from scratch"]
+fn test_each_extension_builds_alone() {
+    let target_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("isolated-builds");
+    for (package, _) in EXTENSIONS {
+        let target_dir = target_dir.join(package);
+        cargo(&["check", "--package", package, "--all-targets", "--target-dir", target_dir.to_str().unwrap()]);
+    }
+}
@@ -0,0 +1,40 @@
+//! Shopify limits the size of a function's `.wasm`, and smaller modules start faster, so each extension's
+//! release module is checked against a size budget. Run it with
+//! `cargo test -p shopify_config --test module_size -- --ignored` after changing dependencies or the
+//! release profile.
+
+use std::path::Path;
+use std::process::Command;
+
+const TARGET: &str = "wasm32-wasip1";
+
+/// The largest each release module may be, in bytes. They're about 5% above the measured sizes, 972521
+/// bytes for delivery and 961689 bytes for payment, so growth gets noticed when it happens.
+const BUDGETS: [(&str, u64); 2] = [("delivery-customization", 1_020_000), ("payment-customization", 1_010_000)];
+
+fn cargo(args: &[&str]) {
+    let output = Command::new(env!("CARGO"))
+        .args(args)
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .expect("cargo should run");
+    assert!(output.status.success(), "cargo {} failed:\n{}", args.join(" "), String::from_utf8_lossy(&output.stderr));
+}
+
+// Slow, since it builds each extension in release mode for the function target
+#[test]
+#[ignore = "builds each extension for wasm32-wasip1, which needs the target installed"]
+fn test_each_release_module_fits_its_size_budget() {
+    let target_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("module-size");
+    for (package, budget) in BUDGETS {
+        cargo(&[
+            "build", "--release", "--package", package, "--bin", package, "--target", TARGET,
+            "--target-dir", target_dir.to_str().unwrap(),
+        ]);
+        let module = target_dir.join(TARGET).join("release").join(format!("{}.wasm", package));
+        let size = std::fs::metadata(&module)
+            .unwrap_or_else(|error| panic!("unable to read {}: {}", module.display(), error))
+            .len();
+        assert!(size <= budget, "{} is {} bytes, over its budget of {} bytes", module.display(), size, budget);
+    }
+}
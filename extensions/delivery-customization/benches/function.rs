@@ -0,0 +1,97 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use serde_json::json;
+
+// The function is a binary, so its source is compiled into the benchmark directly
+#[allow(dead_code)]
+#[path = "../src/main.rs"]
+mod delivery;
+
+// Counts heap allocations, so the benchmark can report how many one run makes
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const GROUPS: usize = 200;
+const ZIPS_PER_RULE: usize = 500;
+const RULES: usize = 20;
+
+// A cart with many delivery groups, each with a handful of options, and a configuration with many
+// rules of many zips, where only some groups match
+fn large_input() -> serde_json::Value {
+    let rules: Vec<_> = (0..RULES)
+        .map(|rule| json!({
+            "zips": (0..ZIPS_PER_RULE).map(|zip| format!("{:05}", rule * ZIPS_PER_RULE + zip)).collect::<Vec<_>>(),
+            "zipPrefix": format!("9{}", rule),
+            "titleContains": "Standard",
+            "message": format!("Rule {} for {{zip}}", rule)
+        }))
+        .collect();
+    let config = json!({ "version": 2, "rules": rules, "ruleSelection": "all", "maxOperations": 1000 });
+    let groups: Vec<_> = (0..GROUPS)
+        .map(|group| {
+            let options: Vec<_> = ["Standard", "Express", "Overnight", "Freight"]
+                .iter()
+                .map(|title| json!({
+                    "handle": format!("{}-{}", title.to_lowercase(), group),
+                    "title": title,
+                    "cost": { "amount": "5.00" }
+                }))
+                .collect();
+            json!({
+                "deliveryAddress": { "countryCode": "US", "zip": format!("{:05}", group * 97) },
+                "deliveryOptions": options
+            })
+        })
+        .collect();
+    json!({
+        "cart": {
+            "cost": { "totalAmount": { "amount": "250.00" } },
+            "lines": [],
+            "deliveryGroups": groups
+        },
+        "localization": { "language": { "isoCode": "EN" } },
+        "deliveryCustomization": {
+            "metafield": { "value": config.to_string() }
+        }
+    })
+}
+
+fn bench_delivery(c: &mut Criterion) {
+    let input = serde_json::from_value(large_input()).unwrap();
+    let config = delivery::safe_config(&input).unwrap();
+
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let result = delivery::apply(&config, &input);
+    println!(
+        "apply: {} operations, {} allocations",
+        result.operations.len(),
+        ALLOCATIONS.load(Ordering::Relaxed) - before
+    );
+
+    c.bench_function("delivery apply", |b| b.iter(|| delivery::apply(&config, &input)));
+    c.bench_function("delivery function", |b| {
+        b.iter_batched(|| input.clone(), delivery::function, BatchSize::LargeInput)
+    });
+}
+
+// Only time and allocations are measured here. The release module's size is checked against a budget
+// by `cargo test -p shopify_config --test module_size -- --ignored`.
+criterion_group!(benches, bench_delivery);
+criterion_main!(benches);
@@ -0,0 +1,72 @@
+// Evaluate a configuration against a sample function input without deploying, printing the result as
+// pretty JSON. The function's logs, including why a configuration is unusable, go to STDERR.
+//
+//     cargo run -p delivery-customization --bin delivery-evaluate -- config.json input.json
+//
+// Either path may be `-` to read it from STDIN. The configuration replaces any metafield in the input.
+//
+// With `--input-query` instead, it prints the input query built for the configured metafield, for deploying
+// a function that reads another metafield.
+
+use std::io::Read;
+use std::process::ExitCode;
+
+// The function is a binary, so its source is compiled into the tool directly
+#[allow(dead_code)]
+#[path = "../main.rs"]
+mod delivery;
+
+const USAGE: &str = "usage: delivery-evaluate <config.json|-> <input.json|->\n       delivery-evaluate --input-query";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args == ["--input-query"] {
+        print!("{}", delivery::INPUT_QUERY);
+        return ExitCode::SUCCESS;
+    }
+    let [config_path, input_path] = args.as_slice() else {
+        eprintln!("{}", USAGE);
+        return ExitCode::FAILURE;
+    };
+    match evaluate(config_path, input_path) {
+        Ok(Some(result)) => {
+            println!("{}", result);
+            ExitCode::SUCCESS
+        }
+        // The function already logged why the configuration is unusable
+        Ok(None) => ExitCode::FAILURE,
+        Err(error) => {
+            eprintln!("{}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+// The pretty-printed result, or None when the configuration is unusable
+fn evaluate(config_path: &str, input_path: &str) -> Result<Option<String>, String> {
+    if config_path == "-" && input_path == "-" {
+        return Err("only one of the configuration and the input can be read from STDIN".to_string());
+    }
+    let config = read(config_path)?;
+    let mut input: serde_json::Value = serde_json::from_str(&read(input_path)?)
+        .map_err(|error| format!("{} is not JSON: {}", input_path, error))?;
+    input["deliveryCustomization"]["metafield"] = serde_json::json!({ "value": config });
+    let input = serde_json::from_value(input)
+        .map_err(|error| format!("{} is not a delivery customization input: {}", input_path, error))?;
+
+    let Some(config) = delivery::safe_config(&input) else {
+        return Ok(None);
+    };
+    let result = delivery::apply(&config, &input);
+    serde_json::to_string_pretty(&result).map(Some).map_err(|error| error.to_string())
+}
+
+fn read(path: &str) -> Result<String, String> {
+    let mut contents = String::new();
+    let read = if path == "-" {
+        std::io::stdin().read_to_string(&mut contents).map(|_| ())
+    } else {
+        std::fs::read_to_string(path).map(|read| contents = read)
+    };
+    read.map(|()| contents).map_err(|error| format!("unable to read {}: {}", path, error))
+}
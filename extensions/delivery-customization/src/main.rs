@@ -1,7 +1,14 @@
 use shopify_function::prelude::*;
 use shopify_function::Result;
 
-use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
+
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::json;
+use shopify_config::logging::{self, Level, Profiler};
+use shopify_config::{money, sort_operations, MessagePosition, MoveTarget, OperationKind};
 
 // Use the shopify_function crate to generate structs for the function input and output
 generate_types!(
@@ -9,63 +16,1278 @@ generate_types!(
     schema_path = "./schema.graphql"
 );
 
-// Create a structure that matches the JSON structure that you'll use for your configuration
+// The input query to deploy the function with: `input.graphql` pointed at the configuration metafield
+// chosen when building. The types above are generated from the tracked query, since the metafield it
+// reads doesn't change them.
+#[cfg_attr(not(test), allow(dead_code))]
+pub(crate) const INPUT_QUERY: &str = include_str!(concat!(env!("OUT_DIR"), "/input.graphql"));
+
+const DEFAULT_SEPARATOR: &str = " - ";
+
+// Keeps a broad match from returning more operations than Shopify accepts in one result
+const DEFAULT_MAX_OPERATIONS: usize = 100;
+
+// Version 1 configurations used `zip` for the zips, version 2 uses `zips`
+const CONFIGURATION_VERSION: u64 = 2;
+
+// Create a structure that matches the JSON structure that you'll use for your configuration.
+// A legacy flat configuration like `{"zip": ..., "message": ...}` is read as a single rule.
+#[derive(Serialize, Deserialize, PartialEq)]
+#[serde(from = "RawConfiguration")]
+pub(crate) struct Configuration {
+    // Always the current version once parsed, since older configurations are migrated first
+    version: u64,
+    // Turns the whole customization off without removing its metafield
+    enabled: bool,
+    rules: Vec<Rule>,
+    // Whether each delivery group gets the first matching rule or all matching rules
+    rule_selection: RuleSelection,
+    // Only customize delivery options for carts totalling at least this amount
+    min_cart_total: Option<money::Decimal>,
+    // Only customize delivery options for carts with at least this many distinct product variants,
+    // however many lines or units each has
+    min_distinct_items: Option<usize>,
+    // Renames the options of groups without a shipping address or zip that no rule matched, built
+    // from the configured `defaultMessageForUnknownAddress`
+    unknown_address_rule: Option<Rule>,
+    // Log the operations instead of applying them, for trying out rules against real carts
+    dry_run: bool,
+    // Log how long each phase of the run took
+    profile: bool,
+    // Operations past this many are dropped, in the order they were produced
+    max_operations: usize,
+    // Added to the title of every free delivery option, whether or not a rule matched its group
+    free_shipping_label: Option<String>,
+    // Names the cart attribute whose value replaces the rules' messages, one of `CART_ATTRIBUTES`
+    attribute_override_key: Option<String>,
+    // The kinds of operations the store's function runtime accepts, all of them by default. Runtimes on
+    // older API versions reject a whole result containing an operation kind they don't support.
+    supported_operations: HashSet<OperationKind>,
+    // Warn about configured zips that don't look like postal codes of their rule's countries
+    validate_zip_format: bool,
+    // Reject configurations with unknown keys instead of ignoring them, to catch typos
+    strict: bool,
+    // The unknown keys, like "zpi" or "rules[1].mesage", sorted
+    unknown_fields: Vec<String>
+}
+
+// No rules, so nothing is customized
+impl Default for Configuration {
+    fn default() -> Self {
+        Configuration {
+            version: CONFIGURATION_VERSION,
+            enabled: true,
+            rules: vec![],
+            rule_selection: RuleSelection::default(),
+            min_cart_total: None,
+            min_distinct_items: None,
+            unknown_address_rule: None,
+            dry_run: false,
+            profile: false,
+            max_operations: DEFAULT_MAX_OPERATIONS,
+            free_shipping_label: None,
+            attribute_override_key: None,
+            supported_operations: OperationKind::ALL.into(),
+            validate_zip_format: false,
+            strict: false,
+            unknown_fields: vec![]
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all(deserialize = "camelCase"))]
+struct RawConfiguration {
+    #[serde(default = "default_enabled")]
+    enabled: bool,
+    #[serde(default)]
+    rules: Vec<Rule>,
+    #[serde(default)]
+    rule_selection: RuleSelection,
+    #[serde(default, deserialize_with = "money::deserialize_optional_decimal")]
+    min_cart_total: Option<money::Decimal>,
+    #[serde(default)]
+    min_distinct_items: Option<usize>,
+    #[serde(default)]
+    default_message_for_unknown_address: Option<String>,
+    #[serde(default)]
+    dry_run: bool,
+    #[serde(default)]
+    profile: bool,
+    #[serde(default)]
+    max_operations: Option<usize>,
+    #[serde(default)]
+    free_shipping_label: Option<String>,
+    #[serde(default)]
+    attribute_override_key: Option<String>,
+    #[serde(default = "default_supported_operations")]
+    supported_operations: HashSet<OperationKind>,
+    #[serde(default)]
+    validate_zip_format: bool,
+    #[serde(default)]
+    strict: bool,
+    #[serde(flatten)]
+    legacy_rule: Rule
+}
+
+impl From<RawConfiguration> for Configuration {
+    fn from(raw: RawConfiguration) -> Self {
+        let mut unknown_fields: Vec<_> = raw.legacy_rule.unknown_fields.keys().cloned().collect();
+        for (index, rule) in raw.rules.iter().enumerate() {
+            unknown_fields.extend(rule.unknown_fields.keys().map(|key| format!("rules[{}].{}", index, key)));
+        }
+        unknown_fields.sort();
+        // A configuration with only top-level settings, like just a free shipping label, has no flat rule
+        // rather than an empty one that fails validation. Its unknown keys were collected above.
+        let mut legacy_rule = raw.legacy_rule;
+        legacy_rule.unknown_fields.clear();
+        let rules = if !raw.rules.is_empty() {
+            raw.rules
+        } else if legacy_rule == Rule::unconfigured() {
+            vec![]
+        } else {
+            vec![legacy_rule]
+        };
+        Configuration {
+            version: CONFIGURATION_VERSION,
+            enabled: raw.enabled,
+            rules,
+            rule_selection: raw.rule_selection,
+            min_cart_total: raw.min_cart_total,
+            min_distinct_items: raw.min_distinct_items,
+            unknown_address_rule: raw.default_message_for_unknown_address
+                .map(|message| Rule {
+                    message,
+                    log_id: "defaultMessageForUnknownAddress".to_string(),
+                    ..Rule::default()
+                }),
+            dry_run: raw.dry_run,
+            profile: raw.profile,
+            max_operations: raw.max_operations.unwrap_or(DEFAULT_MAX_OPERATIONS),
+            free_shipping_label: raw.free_shipping_label,
+            attribute_override_key: raw.attribute_override_key,
+            supported_operations: raw.supported_operations,
+            validate_zip_format: raw.validate_zip_format,
+            strict: raw.strict,
+            unknown_fields
+        }
+    }
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_supported_operations() -> HashSet<OperationKind> {
+    OperationKind::ALL.into()
+}
+
+// A set of delivery group criteria and what to do with the matched delivery options
 #[derive(Serialize, Deserialize, Default, PartialEq)]
 #[serde(rename_all(deserialize = "camelCase"))]
-struct Configuration {
-    zip: String,
-    message: String
+struct Rule {
+    // Names the rule in the logs of the operations it produces, like "hawaii-delays"
+    #[serde(default)]
+    id: Option<String>,
+    // Accepts either a single zip or a list of zips, kept as a set so large lists are cheap to look up
+    #[serde(default, deserialize_with = "shopify_config::deserialize_one_or_many")]
+    zips: HashSet<String>,
+    // Only compare the first this many characters of `zips` and of the group's zip, like 3 for US
+    // sectional center facilities. Zips shorter than that are compared whole.
+    #[serde(default)]
+    zip_match_length: Option<usize>,
+    // Matches every zip starting with this prefix, in addition to `zips`
+    #[serde(default)]
+    zip_prefix: Option<String>,
+    // Inclusive numeric range of zips, configured as a pair of strings like ["90001", "90210"]
+    #[serde(default, deserialize_with = "deserialize_zip_range")]
+    zip_range: Option<(u64, u64)>,
+    // Zips that never match, even when they are also allowed by `zips`, `zip_prefix` or `zip_range`.
+    // On their own they match every address except the ones listed.
+    #[serde(default, deserialize_with = "shopify_config::deserialize_one_or_many")]
+    exclude_zips: HashSet<String>,
+    // Two-letter country codes; empty means any country
+    #[serde(default)]
+    country_codes: Vec<String>,
+    // Province or state codes, compared case-insensitively; empty means any province
+    #[serde(default)]
+    provinces: Vec<String>,
+    // Only apply when the cart contains these products, combined according to the product match mode
+    #[serde(default)]
+    product_ids: Vec<String>,
+    // With `Any`, the default, one of the products in the cart is enough; with `All`, the cart must
+    // contain every one of them
+    #[serde(default = "default_product_match_mode")]
+    product_match_mode: LogicMode,
+    // Only match zips matching this regular expression, in addition to the other criteria
+    #[serde(default)]
+    zip_regex: Option<String>,
+    // Only customize delivery options whose title contains this text, compared case-insensitively
+    #[serde(default)]
+    title_contains: Option<String>,
+    // Only customize delivery options whose title matches this regular expression, like "^(standard|economy)"
+    #[serde(default)]
+    title_regex: Option<String>,
+    // Only customize delivery options whose handle starts with this, like "shopify-Standard-" for
+    // carrier-calculated rates. Unlike titles, handles aren't localized.
+    #[serde(default)]
+    handle_prefix: Option<String>,
+    // Only customize delivery options costing at least this much, like premium or express options
+    #[serde(default, deserialize_with = "money::deserialize_optional_decimal")]
+    min_option_cost: Option<money::Decimal>,
+    // Not needed when hiding options
+    #[serde(default)]
+    message: String,
+    // Replaces the whole option title, like "Economy (3–5 days)", instead of adding the message to it
+    #[serde(default)]
+    replace_title: Option<String>,
+    // Messages for specific zips, like {"96799": "Island - 10 day delay"}, used instead of `message`
+    #[serde(default)]
+    zip_messages: HashMap<String, String>,
+    // Messages for groups of zips, like [[["10001", "10002"], "Congested - expect delays"]], checked in
+    // order after `zip_messages` so the first tier containing the zip wins
+    #[serde(default)]
+    tiered_messages: Vec<(Vec<String>, String)>,
+    // Messages for specific options, keyed by their handle or original title, like
+    // {"Express": "Arrives tomorrow"}, used instead of the zip and group messages
+    #[serde(default)]
+    option_messages: HashMap<String, String>,
+    // Messages keyed by the buyer's language code, like {"FR": "Livraison gratuite"}, used instead of
+    // `message`. Zip messages still take precedence.
+    #[serde(default)]
+    messages_by_locale: HashMap<String, String>,
+    // Messages for carts totaling more than a threshold, like [[50, "Small surcharge"], [200, "Large
+    // surcharge"]], where the highest threshold the cart exceeds wins. Any other message for the option,
+    // zip or language takes precedence.
+    #[serde(default, deserialize_with = "money::deserialize_decimal_tiers")]
+    message_tiers: Vec<(money::Decimal, String)>,
+    // Whether the message goes before or after the original option title
+    #[serde(default)]
+    message_position: MessagePosition,
+    // Placed between the original title and the message, defaults to " - "
+    #[serde(default)]
+    separator: Option<String>,
+    // Whether every configured condition must match, or any one of them
+    #[serde(default)]
+    condition_logic: LogicMode,
+    // What to do with the matched delivery options
+    #[serde(default)]
+    mode: DeliveryAction,
+    // Customize every option of every delivery group, overriding the address, cart and title filters
+    #[serde(default)]
+    match_all: bool,
+    // Also move the options this rule renames, like "top" or {"index": 2}
+    #[serde(default)]
+    move_to: Option<MoveTarget>,
+    // Names a cart attribute that turns the rule off when it's set to anything but "", "false", "no" or
+    // "0", like a marker an earlier checkout step sets once its note is shown. One of `CART_ATTRIBUTES`.
+    #[serde(default)]
+    skip_if_attribute: Option<String>,
+    // Keys this rule doesn't know, like a misspelled `zpi`, which strict configurations reject. The
+    // unknown top-level keys of a configuration end up in its flat rule.
+    #[serde(flatten, skip_serializing)]
+    unknown_fields: HashMap<String, serde_json::Value>,
+    // The compiled `zip_regex` and `title_regex`, filled in by `compile_patterns`
+    #[serde(skip)]
+    patterns: RulePatterns,
+    // The `id`, or the rule's position like "rules[1]" without one, filled in by `identify`
+    #[serde(skip)]
+    log_id: String,
+    // The first `zip_match_length` characters of each of `zips`, filled in by `normalize`
+    #[serde(skip)]
+    zip_sections: HashSet<String>
+}
+
+#[derive(Default)]
+struct RulePatterns {
+    zip: Option<Regex>,
+    title: Option<Regex>
+}
+
+impl PartialEq for RulePatterns {
+    fn eq(&self, other: &Self) -> bool {
+        let source = |regex: &Option<Regex>| regex.as_ref().map(|regex| regex.as_str().to_string());
+        source(&self.zip) == source(&other.zip) && source(&self.title) == source(&other.title)
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, PartialEq, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+enum LogicMode {
+    // A delivery group matches when it meets every configured condition, or the cart contains every
+    // configured product
+    #[default]
+    All,
+    // A delivery group matches when it meets at least one configured condition, or the cart contains
+    // at least one configured product
+    Any
+}
+
+fn default_product_match_mode() -> LogicMode {
+    LogicMode::Any
+}
+
+#[derive(Serialize, Deserialize, Default, PartialEq, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+enum RuleSelection {
+    // Only the first rule matching a delivery group applies to it
+    #[default]
+    First,
+    // Every rule matching a delivery group applies to it, with renames applied in rule order
+    All
+}
+
+#[derive(Serialize, Deserialize, Default, PartialEq, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+enum DeliveryAction {
+    #[default]
+    Rename,
+    Hide,
+    // Like {"move": {"index": 2}} or {"move": "bottom"}, where the bottom is the last of the group's options
+    Move(MoveTarget)
+}
+
+// Parse both ends of the zip range as integers, rejecting inverted ranges
+fn deserialize_zip_range<'de, D>(deserializer: D) -> std::result::Result<Option<(u64, u64)>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let range = match Option::<(String, String)>::deserialize(deserializer)? {
+        Some(range) => range,
+        None => return Ok(None),
+    };
+    let parse_bound = |bound: &str| {
+        bound.trim().parse::<u64>().map_err(|_| {
+            serde::de::Error::custom(format!("zip range bound \"{}\" is not numeric", bound))
+        })
+    };
+    let (low, high) = (parse_bound(&range.0)?, parse_bound(&range.1)?);
+    if low > high {
+        return Err(serde::de::Error::custom(format!(
+            "zip range is inverted: low bound {} exceeds high bound {}",
+            range.0, range.1
+        )));
+    }
+    Ok(Some((low, high)))
+}
+
+// The parts of the cart that rules are evaluated against, gathered once per run
+struct CartContext<'a> {
+    product_ids: HashSet<String>,
+    // Lines of the same variant count once
+    distinct_items: usize,
+    // The values of the selected cart attributes that are set, keyed by attribute
+    attributes: HashMap<&'static str, &'a str>
+}
+
+// What a rule's message is chosen by and filled in with, and how many options moves are placed among,
+// for one delivery group
+struct GroupContext<'a> {
+    zip: Option<&'a str>,
+    language: Option<&'a str>,
+    // The cart attribute value replacing every rule's message
+    message_override: Option<&'a str>,
+    // Missing when the cart total doesn't parse, which leaves the cart total tiers unused
+    cart_total: Option<money::Decimal>,
+    option_count: usize,
+    // The group's position among the cart's delivery groups, from 0
+    group_index: usize
+}
+
+// The cart attributes that configurations can name, each selected in `input.graphql` by its key. An
+// attribute has to be added both here and to the query.
+const CART_ATTRIBUTES: &[(&str, AttributeValue)] = &[
+    ("delivery_note_override", |cart| cart.attribute.as_ref().and_then(|attribute| attribute.value.as_deref())),
+    ("delivery_note_applied", |cart| {
+        cart.delivery_note_applied.as_ref().and_then(|attribute| attribute.value.as_deref())
+    }),
+];
+
+type AttributeValue = fn(&input::InputCart) -> Option<&str>;
+
+impl<'a> CartContext<'a> {
+    fn new(cart: &'a input::InputCart) -> Self {
+        let variants: Vec<_> = cart.lines
+            .iter()
+            .filter_map(|line| match &line.merchandise {
+                input::InputCartLinesMerchandise::ProductVariant(variant) => Some(variant),
+                _ => None
+            })
+            .collect();
+        let product_ids = variants.iter().map(|variant| variant.product.id.to_string()).collect();
+        let distinct_items = variants.iter().map(|variant| variant.id.as_str()).collect::<HashSet<_>>().len();
+        let attributes = CART_ATTRIBUTES.iter()
+            .filter_map(|&(key, value)| Some((key, value(cart)?)))
+            .collect();
+        CartContext { product_ids, distinct_items, attributes }
+    }
+}
+
+// Only the cart attributes the input query selects can be read, so any other key would never be set
+fn is_cart_attribute(key: &str) -> bool {
+    CART_ATTRIBUTES.iter().any(|(selected, _)| *selected == key)
+}
+
+fn supported_attributes() -> String {
+    let keys: Vec<_> = CART_ATTRIBUTES.iter().map(|(key, _)| *key).collect();
+    format!("the supported attributes are {}", keys.join(", "))
+}
+
+// Whether a cart attribute value switches something on. Attributes are free-form text, so the usual ways of
+// writing "off" are read as off too.
+fn is_truthy(value: &str) -> bool {
+    let value = value.trim();
+    !value.is_empty() && !["false", "no", "0"].iter().any(|off| value.eq_ignore_ascii_case(off))
 }
 
 // Parse the JSON metafield value using serde
 impl Configuration {
-    fn from_str(value: &str) -> Self {
-        serde_json::from_str(value).expect("Unable to parse configuration value from metafield")
+    // Migrate older configuration versions before deserializing. A newer version than this function
+    // knows about falls back to the default configuration, which leaves every delivery option as it is.
+    fn from_str(value: &str) -> std::result::Result<Self, serde_json::Error> {
+        let mut value: serde_json::Value = shopify_config::parse_config(value)?;
+        match shopify_config::config_version(&value) {
+            1 => shopify_config::rename_rule_keys(&mut value, &[("zip", "zips")]),
+            CONFIGURATION_VERSION => {}
+            version => {
+                log(Level::Warn, "Unsupported configuration version, falling back to the default configuration", &[
+                    ("version", json!(version)),
+                    ("supported_version", json!(CONFIGURATION_VERSION)),
+                ]);
+                return Ok(Configuration::default());
+            }
+        }
+        // The version was only needed to migrate the configuration, so it isn't an unknown key
+        if let Some(object) = value.as_object_mut() {
+            object.remove("version");
+        }
+        let mut config: Configuration = serde_json::from_value(value)?;
+        if config.strict && !config.unknown_fields.is_empty() {
+            let message = format!("unknown fields in strict configuration: {}", config.unknown_fields.join(", "));
+            return Err(serde::de::Error::custom(message));
+        }
+        config.prepare();
+        Ok(config)
+    }
+
+    // Fill in what the rules derive from their parsed fields: ids, normalized zips and compiled patterns
+    fn prepare(&mut self) {
+        self.rules.iter_mut().enumerate().for_each(|(index, rule)| rule.identify(index));
+        self.rules.iter_mut().for_each(Rule::normalize);
+        self.rules.iter_mut().for_each(Rule::compile_patterns);
+    }
+
+    // Check the invariants serde can't express, collecting every problem so they can all be fixed at
+    // once. Invalid regexes aren't errors, since rules ignore them.
+    fn validate(&self) -> std::result::Result<(), Vec<String>> {
+        let mut errors = vec![];
+        if self.min_cart_total.is_some_and(|min_cart_total| min_cart_total.is_sign_negative()) {
+            errors.push("minCartTotal must not be negative".to_string());
+        }
+        if self.max_operations == 0 {
+            errors.push("maxOperations must be at least 1".to_string());
+        }
+        if self.unknown_address_rule.as_ref().is_some_and(|rule| rule.message.is_empty()) {
+            errors.push("defaultMessageForUnknownAddress must not be empty".to_string());
+        }
+        if self.free_shipping_label.as_deref().is_some_and(str::is_empty) {
+            errors.push("freeShippingLabel must not be empty".to_string());
+        }
+        if self.attribute_override_key.as_deref().is_some_and(str::is_empty) {
+            errors.push("attributeOverrideKey must not be empty".to_string());
+        } else if let Some(key) = self.attribute_override_key.as_deref().filter(|key| !is_cart_attribute(key)) {
+            errors.push(format!("attributeOverrideKey {:?} isn't selected, {}", key, supported_attributes()));
+        }
+        if self.supported_operations.is_empty() {
+            errors.push("supportedOperations must not be empty".to_string());
+        }
+        for (index, rule) in self.rules.iter().enumerate() {
+            rule.validate(&format!("rules[{}]", index), &mut errors);
+            if self.validate_zip_format {
+                rule.warn_about_zip_formats(&format!("rules[{}]", index));
+            }
+        }
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+
+    // The rules that apply to a delivery group's address and the cart, according to the rule selection
+    fn matching_rules(
+        &self,
+        address: Option<&input::InputCartDeliveryGroupsDeliveryAddress>,
+        cart: &CartContext
+    ) -> Vec<&Rule> {
+        let matching = self.rules.iter()
+            .filter(|rule| rule.matches(address, cart));
+        match self.rule_selection {
+            RuleSelection::First => matching.take(1).collect(),
+            RuleSelection::All => matching.collect()
+        }
+    }
+}
+
+impl Rule {
+    // A rule without any of its keys set, with the defaults serde fills in
+    fn unconfigured() -> Self {
+        serde_json::from_value(json!({})).expect("a rule without keys should deserialize")
+    }
+
+    // A zip matches when it is one of the configured zips, starts with the configured prefix,
+    // or falls within the configured numeric range. An empty prefix matches nothing rather than
+    // everything, and non-numeric zips never match the range.
+    fn matches_zip(&self, code: &str) -> bool {
+        let matches_prefix = self.zip_prefix.as_ref()
+            .is_some_and(|prefix| !prefix.is_empty() && code.starts_with(prefix.as_str()));
+        let matches_range = self.zip_range.is_some_and(|(low, high)| {
+            code.parse::<u64>().is_ok_and(|zip| (low..=high).contains(&zip))
+        });
+        let matches_zips = match self.zip_match_length {
+            Some(length) => self.zip_sections.contains(zip_section(code, length)),
+            None => self.zips.contains(code)
+        };
+        matches_zips || matches_prefix || matches_range
+    }
+
+    // Name the rule in logs by its id, falling back to its position among the configuration's rules
+    fn identify(&mut self, index: usize) {
+        self.log_id = self.id.clone().unwrap_or_else(|| format!("rules[{}]", index));
+    }
+
+    // Normalize the whitespace of the configured zips that serde doesn't already normalize: the zip
+    // prefix and the zips that have their own messages. Group zips are normalized the same way before
+    // they're compared.
+    fn normalize(&mut self) {
+        if let Some(prefix) = &mut self.zip_prefix {
+            *prefix = shopify_config::normalize_whitespace(prefix).into_owned();
+        }
+        if let Some(length) = self.zip_match_length {
+            self.zip_sections = self.zips.iter().map(|zip| zip_section(zip, length).to_string()).collect();
+        }
+        self.zip_messages = std::mem::take(&mut self.zip_messages)
+            .into_iter()
+            .map(|(zip, message)| (shopify_config::normalize_whitespace(&zip).into_owned(), message))
+            .collect();
+        for (zips, _) in &mut self.tiered_messages {
+            for zip in zips.iter_mut() {
+                *zip = shopify_config::normalize_whitespace(zip).into_owned();
+            }
+        }
+        // Ascending, so the last tier the cart total exceeds is the highest
+        self.message_tiers.sort_by_key(|(threshold, _)| *threshold);
+    }
+
+    // Compile the rule's regular expressions once per run. An invalid pattern is logged and ignored,
+    // leaving the rule to match on its other criteria.
+    fn compile_patterns(&mut self) {
+        let compile = |field: &str, pattern: &Option<String>| {
+            let pattern = pattern.as_ref()?;
+            Regex::new(pattern).map_err(|error| {
+                log(Level::Warn, "Unable to compile regex, ignoring it", &[
+                    ("field", json!(field)),
+                    ("pattern", json!(pattern)),
+                    ("error", json!(error.to_string())),
+                ]);
+            }).ok()
+        };
+        self.patterns = RulePatterns {
+            zip: compile("zipRegex", &self.zip_regex),
+            title: compile("titleRegex", &self.title_regex)
+        };
+    }
+
+    fn validate(&self, path: &str, errors: &mut Vec<String>) {
+        let is_zip = |zip: &str| zip.chars().all(|c| c.is_ascii_alphanumeric() || c == ' ' || c == '-');
+        // Sorted so the errors come out in the same order on every run
+        let mut zips: Vec<_> = self.zips.iter().chain(&self.exclude_zips).collect();
+        zips.sort();
+        for zip in zips {
+            if !is_zip(zip) {
+                errors.push(format!("{}: zip {:?} may only contain letters, digits, spaces and dashes", path, zip));
+            }
+        }
+        if self.id.as_deref().is_some_and(str::is_empty) {
+            errors.push(format!("{}: id must not be empty", path));
+        }
+        if self.zip_match_length == Some(0) {
+            errors.push(format!("{}: zipMatchLength must be at least 1", path));
+        }
+        if self.zip_prefix.as_deref().is_some_and(|prefix| prefix.is_empty() || !is_zip(prefix)) {
+            errors.push(format!("{}: zipPrefix must be a non-empty zip prefix", path));
+        }
+        if self.title_contains.as_deref().is_some_and(str::is_empty) {
+            errors.push(format!("{}: titleContains must not be empty", path));
+        }
+        if self.handle_prefix.as_deref().is_some_and(str::is_empty) {
+            errors.push(format!("{}: handlePrefix must not be empty", path));
+        }
+        if self.min_option_cost.is_some_and(|min_option_cost| min_option_cost.is_sign_negative()) {
+            errors.push(format!("{}: minOptionCost must not be negative", path));
+        }
+        if self.tiered_messages.iter().any(|(_, message)| message.is_empty()) {
+            errors.push(format!("{}: tieredMessages must not have empty messages", path));
+        }
+        if self.message_tiers.iter().any(|(threshold, _)| threshold.is_sign_negative()) {
+            errors.push(format!("{}: messageTiers must not have negative thresholds", path));
+        }
+        if self.message_tiers.iter().any(|(_, message)| message.is_empty()) {
+            errors.push(format!("{}: messageTiers must not have empty messages", path));
+        }
+        if self.option_messages.values().any(String::is_empty) {
+            errors.push(format!("{}: optionMessages must not have empty messages", path));
+        }
+        if self.replace_title.as_deref().is_some_and(str::is_empty) {
+            errors.push(format!("{}: replaceTitle must not be empty", path));
+        }
+        if self.replace_title.is_some() && self.mode != DeliveryAction::Rename {
+            errors.push(format!("{}: replaceTitle only applies when renaming", path));
+        }
+        let has_message = self.replace_title.is_some()
+            || !self.message.is_empty()
+            || !self.zip_messages.is_empty()
+            || !self.tiered_messages.is_empty()
+            || !self.option_messages.is_empty()
+            || !self.messages_by_locale.is_empty()
+            || !self.message_tiers.is_empty();
+        if self.mode == DeliveryAction::Rename && !has_message {
+            errors.push(format!("{}: message is required when renaming", path));
+        }
+        if self.move_to.is_some() && self.mode != DeliveryAction::Rename {
+            errors.push(format!("{}: moveTo only applies when renaming", path));
+        }
+        if self.skip_if_attribute.as_deref().is_some_and(str::is_empty) {
+            errors.push(format!("{}: skipIfAttribute must not be empty", path));
+        } else if let Some(key) = self.skip_if_attribute.as_deref().filter(|key| !is_cart_attribute(key)) {
+            errors.push(format!("{}: skipIfAttribute {:?} isn't selected, {}", path, key, supported_attributes()));
+        }
+    }
+
+    // Log the configured zips that match none of the formats of the rule's countries, which are likely
+    // typos. Legitimate zips can still fall outside these formats, so they're only warned about.
+    // Countries without a known format are skipped.
+    fn warn_about_zip_formats(&self, path: &str) {
+        let formats: Vec<_> = self.country_codes.iter()
+            .filter_map(|country_code| zip_format(country_code))
+            .filter_map(|pattern| Regex::new(pattern).ok())
+            .collect();
+        if formats.is_empty() {
+            return;
+        }
+        let mut zips: Vec<_> = self.zips.iter().chain(&self.exclude_zips).collect();
+        zips.sort();
+        for zip in zips.into_iter().filter(|zip| !formats.iter().any(|format| format.is_match(zip))) {
+            log(Level::Warn, "Configured zip doesn't look like a postal code of the rule's countries", &[
+                ("rule", json!(path)),
+                ("zip", json!(zip)),
+                ("country_codes", json!(self.country_codes)),
+            ]);
+        }
+    }
+
+    fn has_zip_criteria(&self) -> bool {
+        !self.zips.is_empty() || self.zip_prefix.is_some() || self.zip_range.is_some()
+    }
+
+    // A delivery group matches when its address and the cart satisfy the configured conditions (zips,
+    // the zip regex, excluded zips, country codes, provinces and product ids), combined according to
+    // the condition logic. Conditions left empty are ignored, and nothing matches when none are set.
+    // With `All`, an excluded zip wins over an allowed one. Groups without an address only match
+    // rules matching everything. A rule whose skip attribute is set on the cart matches nothing, even
+    // when it matches everything.
+    fn matches(&self, address: Option<&input::InputCartDeliveryGroupsDeliveryAddress>, cart: &CartContext) -> bool {
+        let skip_attribute = self.skip_if_attribute.as_deref().and_then(|key| cart.attributes.get(key));
+        if skip_attribute.is_some_and(|value| is_truthy(value)) {
+            return false;
+        }
+        if self.match_all {
+            return true;
+        }
+        let Some(address) = address else {
+            return false;
+        };
+        let zip = address.zip.as_deref().map(shopify_config::normalize_whitespace);
+        let matches_zip = || match zip.as_deref() {
+            Some(code) => self.matches_zip(code),
+            None => false
+        };
+        let matches_zip_regex = || self.patterns.zip.as_ref()
+            .is_some_and(|regex| zip.as_deref().is_some_and(|code| regex.is_match(code)));
+        let not_excluded = || !zip.as_deref()
+            .is_some_and(|code| self.exclude_zips.contains(code));
+        let matches_country = || address.country_code.as_ref()
+            .and_then(shopify_config::enum_value)
+            .is_some_and(|code| self.country_codes.iter().any(|configured| configured == &code));
+        let matches_province = || address.province_code.as_ref()
+            .is_some_and(|code| self.provinces.iter().any(|configured| configured.eq_ignore_ascii_case(code)));
+        let matches_products = || match self.product_match_mode {
+            LogicMode::All => self.product_ids.iter().all(|id| cart.product_ids.contains(id)),
+            LogicMode::Any => self.product_ids.iter().any(|id| cart.product_ids.contains(id))
+        };
+
+        // Only evaluate the conditions that are configured
+        let conditions: [(bool, &dyn Fn() -> bool); 6] = [
+            (self.has_zip_criteria(), &matches_zip),
+            (self.patterns.zip.is_some(), &matches_zip_regex),
+            (!self.exclude_zips.is_empty(), &not_excluded),
+            (!self.country_codes.is_empty(), &matches_country),
+            (!self.provinces.is_empty(), &matches_province),
+            (!self.product_ids.is_empty(), &matches_products),
+        ];
+        let mut configured = conditions.iter()
+            .filter(|(configured, _)| *configured)
+            .map(|(_, condition)| condition)
+            .peekable();
+        configured.peek().is_some() && match self.condition_logic {
+            LogicMode::All => configured.all(|condition| condition()),
+            LogicMode::Any => configured.any(|condition| condition())
+        }
+    }
+
+    // Whether a delivery option in a matched group should be customized by this rule
+    fn matches_option(&self, option: &input::InputCartDeliveryGroupsDeliveryOptions) -> bool {
+        if self.match_all {
+            return true;
+        }
+        let contains_text = match &self.title_contains {
+            Some(text) => option.title.as_ref()
+                .is_some_and(|title| title.to_lowercase().contains(&text.to_lowercase())),
+            None => true
+        };
+        let matches_handle = self.handle_prefix.as_ref()
+            .is_none_or(|prefix| option.handle.starts_with(prefix.as_str()));
+        let matches_regex = match &self.patterns.title {
+            Some(regex) => option.title.as_ref().is_some_and(|title| regex.is_match(title)),
+            None => true
+        };
+        // An option whose cost can't be read doesn't meet the minimum
+        let meets_min_cost = match self.min_option_cost {
+            Some(min_option_cost) => money::parse_decimal(&option.cost.amount).is_some_and(|cost| cost >= min_option_cost),
+            None => true
+        };
+        contains_text && matches_handle && matches_regex && meets_min_cost
+    }
+
+    // The cart's override message, then the message configured for the option, then for the zip or its
+    // tier, then for the buyer's language, then for the cart total's tier, falling back to the rule's
+    // message
+    fn message_for<'a>(
+        &'a self,
+        option: &input::InputCartDeliveryGroupsDeliveryOptions,
+        context: &GroupContext<'a>
+    ) -> &'a str {
+        if let Some(message) = context.message_override {
+            return message;
+        }
+        self.option_message(option)
+            .or_else(|| context.zip.and_then(|zip| self.zip_messages.get(zip).or_else(|| self.tiered_message(zip))))
+            .or_else(|| context.language.and_then(|language| self.messages_by_locale.get(language)))
+            .or_else(|| context.cart_total.and_then(|cart_total| self.message_tier(cart_total)))
+            .unwrap_or(&self.message)
+    }
+
+    // The message of the highest tier the cart total exceeds
+    fn message_tier(&self, cart_total: money::Decimal) -> Option<&String> {
+        self.message_tiers.iter()
+            .rev()
+            .find(|(threshold, _)| cart_total > *threshold)
+            .map(|(_, message)| message)
+    }
+
+    // The message for the option's handle, then for its original title
+    fn option_message(&self, option: &input::InputCartDeliveryGroupsDeliveryOptions) -> Option<&String> {
+        self.option_messages.get(option.handle.as_str())
+            .or_else(|| option.title.as_deref().and_then(|title| self.option_messages.get(title)))
+    }
+
+    // The message of the first tier containing the zip
+    fn tiered_message(&self, zip: &str) -> Option<&String> {
+        self.tiered_messages.iter()
+            .find(|(zips, _)| zips.iter().any(|tier_zip| tier_zip == zip))
+            .map(|(_, message)| message)
+    }
+
+    // The message for the group with its placeholders filled in. The message may reference the group's
+    // `{zip}`, `{option_count}` and `{group_index}`, and the option's original `{title}`.
+    fn interpolated_message(
+        &self,
+        option: &input::InputCartDeliveryGroupsDeliveryOptions,
+        title: &str,
+        context: &GroupContext
+    ) -> String {
+        let option_count = context.option_count.to_string();
+        let group_index = context.group_index.to_string();
+        substitute_placeholders(self.message_for(option, context), &[
+            ("zip", context.zip.unwrap_or_default()),
+            ("title", title),
+            ("option_count", &option_count),
+            ("group_index", &group_index),
+        ])
+    }
+
+    // Combine the original option title with the message, or use the message alone without a title. A
+    // replacement title is used verbatim instead.
+    fn rename_title(
+        &self,
+        option: &input::InputCartDeliveryGroupsDeliveryOptions,
+        title: Option<&str>,
+        context: &GroupContext
+    ) -> String {
+        if let Some(replace_title) = &self.replace_title {
+            return replace_title.clone();
+        }
+        let message = self.interpolated_message(option, title.unwrap_or_default(), context);
+        let separator = self.separator.as_deref().unwrap_or(DEFAULT_SEPARATOR);
+        match (title, self.message_position) {
+            (Some(title), MessagePosition::Prefix) => format!("{}{}{}", message, separator, title),
+            (Some(title), MessagePosition::Suffix) => format!("{}{}{}", title, separator, message),
+            (None, _) => message
+        }
+    }
+
+    // Whether the title already carries this rule's message in the configured position, as it does
+    // when Shopify runs the function again on an option it already renamed, or already is the
+    // replacement title. Messages referencing `{title}` can't be recognized this way.
+    fn is_renamed(
+        &self,
+        option: &input::InputCartDeliveryGroupsDeliveryOptions,
+        title: &str,
+        context: &GroupContext
+    ) -> bool {
+        if let Some(replace_title) = &self.replace_title {
+            return title == replace_title;
+        }
+        let message = self.interpolated_message(option, title, context);
+        let separator = self.separator.as_deref().unwrap_or(DEFAULT_SEPARATOR);
+        match self.message_position {
+            MessagePosition::Prefix => title.starts_with(&format!("{}{}", message, separator)),
+            MessagePosition::Suffix => title.ends_with(&format!("{}{}", separator, message))
+        }
+    }
+}
+
+// The first `length` characters of a zip, or the whole zip when it's shorter
+fn zip_section(zip: &str, length: usize) -> &str {
+    zip.char_indices().nth(length).map_or(zip, |(index, _)| &zip[..index])
+}
+
+// A pattern for the postal codes of a country, like "A1A 1A1" in Canada, for the countries merchants
+// configure most often
+fn zip_format(country_code: &str) -> Option<&'static str> {
+    match country_code {
+        "US" => Some(r"^\d{5}(-\d{4})?$"),
+        "CA" => Some(r"(?i)^[A-Z]\d[A-Z] ?\d[A-Z]\d$"),
+        "GB" => Some(r"(?i)^[A-Z]{1,2}\d[A-Z\d]? ?\d[A-Z]{2}$"),
+        "AU" => Some(r"^\d{4}$"),
+        "DE" | "FR" | "ES" | "IT" => Some(r"^\d{5}$"),
+        "NL" => Some(r"(?i)^\d{4} ?[A-Z]{2}$"),
+        "JP" => Some(r"^\d{3}-?\d{4}$"),
+        _ => None
+    }
+}
+
+// Replace `{name}` placeholders with their values in a single pass, leaving unknown placeholders as-is.
+// It only slices at the ASCII braces, so multi-byte characters around placeholders stay intact.
+fn substitute_placeholders(message: &str, values: &[(&str, &str)]) -> String {
+    let mut result = String::with_capacity(message.len());
+    let mut rest = message;
+    while let Some(start) = rest.find('{') {
+        result.push_str(&rest[..start]);
+        let candidate = &rest[start..];
+        let replacement = candidate.find('}').and_then(|end| {
+            let name = &candidate[1..end];
+            values.iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| (*value, end + 1))
+        });
+        match replacement {
+            Some((value, consumed)) => {
+                result.push_str(value);
+                rest = &candidate[consumed..];
+            }
+            None => {
+                result.push('{');
+                rest = &candidate[1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+// Write a structured log entry to STDERR, which shows up in the function's run logs
+fn log(level: Level, message: &str, fields: &[(&str, serde_json::Value)]) {
+    let entry = logging::entry(level, message, fields);
+    #[cfg(test)]
+    LOGS.with(|logs| logs.borrow_mut().push(entry.clone()));
+    eprintln!("{}", entry);
+}
+
+// Write a metric entry to STDERR with the log entries, which dashboards aggregate across runs
+fn metric(name: &str, values: &[(&str, serde_json::Value)]) {
+    let entry = logging::metric(name, values);
+    #[cfg(test)]
+    METRICS.with(|metrics| metrics.borrow_mut().push(entry.clone()));
+    eprintln!("{}", entry);
+}
+
+// Entries logged and metrics written by the current test thread
+#[cfg(test)]
+thread_local! {
+    static LOGS: std::cell::RefCell<Vec<String>> = const { std::cell::RefCell::new(Vec::new()) };
+    static METRICS: std::cell::RefCell<Vec<String>> = const { std::cell::RefCell::new(Vec::new()) };
+}
+
+// Environment variable holding a configuration that supersedes the metafield, for trying out staging
+// configurations locally
+const CONFIG_OVERRIDE_VARIABLE: &str = "FUNCTION_CONFIG_OVERRIDE";
+
+// The configuration override is only compiled into tests and builds with the `config-override`
+// feature, so deployed functions always read the metafield
+#[cfg(any(test, feature = "config-override"))]
+fn config_override() -> Option<String> {
+    // Tests run in parallel within one process, so only the threads opting in read the variable
+    #[cfg(test)]
+    if !READ_CONFIG_OVERRIDE.with(|read| read.get()) {
+        return None;
+    }
+    std::env::var(CONFIG_OVERRIDE_VARIABLE).ok()
+}
+
+#[cfg(not(any(test, feature = "config-override")))]
+fn config_override() -> Option<String> {
+    None
+}
+
+// Whether the current test thread reads the configuration override
+#[cfg(test)]
+thread_local! {
+    static READ_CONFIG_OVERRIDE: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+// Log which rule produced an operation, so operators can tell which of several matching rules caused it
+fn log_rule_operation(rule: &Rule, operation: &output::Operation) {
+    if let Some((kind, handle)) = operation_key(operation) {
+        log(Level::Info, "Rule produced an operation.", &[
+            ("rule", json!(rule.log_id)),
+            ("kind", json!(kind)),
+            ("delivery_option_handle", json!(handle)),
+        ]);
+    }
+}
+
+// Renames from several rules and the free shipping label are chained onto the same title so that one
+// rename is emitted per option. The function then sorts every rename ahead of every hide, each by handle,
+// and keeps the moves last in rule order. Each operation comes with the rules that produced it, none for
+// a rename that only adds the free shipping label.
+fn option_operations<'a>(
+    option: &input::InputCartDeliveryGroupsDeliveryOptions,
+    rules: &[&'a Rule],
+    free_shipping_label: Option<&str>,
+    context: &GroupContext
+) -> Vec<(Vec<&'a Rule>, output::Operation)> {
+    let mut operations = vec![];
+    let mut renamed_title: Option<String> = None;
+    let mut renamed_by = vec![];
+    for &rule in rules.iter().filter(|rule| rule.matches_option(option)) {
+        match rule.mode {
+            // Add the message to the option title, on top of any earlier rule's rename, unless the
+            // title already has it
+            DeliveryAction::Rename => {
+                // A rule with messages only for some options, and no message of its own, leaves the
+                // other options alone
+                if rule.replace_title.is_none() && rule.message_for(option, context).is_empty() {
+                    continue;
+                }
+                let title = renamed_title.as_deref().or(option.title.as_deref());
+                if !title.is_some_and(|title| rule.is_renamed(option, title, context)) {
+                    renamed_title = Some(rule.rename_title(option, title, context));
+                    renamed_by.push(rule);
+                }
+                // Shopify takes one action per operation, so the move is a separate operation after
+                // the rename, with its own de-duplication key
+                if let Some(target) = rule.move_to {
+                    operations.push((vec![rule], move_operation(option, target, context)));
+                }
+            }
+            DeliveryAction::Hide => {
+                operations.push((vec![rule], output::Operation::hide(output::HideOperation {
+                    delivery_option_handle: option.handle.to_string()
+                })));
+            }
+            DeliveryAction::Move(target) => {
+                operations.push((vec![rule], move_operation(option, target, context)));
+            }
+        }
+    }
+    // Label free options after the rules' renames, unless the title already ends with the label
+    if let Some(label) = free_shipping_label.filter(|_| is_free(option)) {
+        let title = renamed_title.as_deref().or(option.title.as_deref());
+        let suffix = format!("{}{}", DEFAULT_SEPARATOR, label);
+        if !title.is_some_and(|title| title.ends_with(&suffix)) {
+            renamed_title = Some(match title {
+                Some(title) => format!("{}{}", title, suffix),
+                None => label.to_string()
+            });
+        }
+    }
+    if let Some(title) = renamed_title {
+        operations.insert(0, (renamed_by, output::Operation::rename(output::RenameOperation {
+            delivery_option_handle: option.handle.to_string(),
+            title
+        })));
+    }
+    operations
+}
+
+fn move_operation(
+    option: &input::InputCartDeliveryGroupsDeliveryOptions,
+    target: MoveTarget,
+    context: &GroupContext
+) -> output::Operation {
+    output::Operation::move_to(output::MoveOperation {
+        delivery_option_handle: option.handle.to_string(),
+        index: target.index(context.option_count)
+    })
+}
+
+// Whether a delivery option costs nothing, treating an unparseable cost as paid
+fn is_free(option: &input::InputCartDeliveryGroupsDeliveryOptions) -> bool {
+    money::parse_decimal(&option.cost.amount).is_some_and(|cost| cost.is_zero())
+}
+
+// Shopify applies one action per operation, so each constructor leaves the other actions unset
+impl output::Operation {
+    fn rename(rename: output::RenameOperation) -> Self {
+        output::Operation { rename: Some(rename), hide: None, move_: None }
+    }
+
+    fn hide(hide: output::HideOperation) -> Self {
+        output::Operation { rename: None, hide: Some(hide), move_: None }
+    }
+
+    fn move_to(move_: output::MoveOperation) -> Self {
+        output::Operation { rename: None, hide: None, move_: Some(move_) }
+    }
+}
+
+// Operations of the same kind for the same delivery option conflict, so only the first is kept. An
+// operation without an action has no key.
+fn operation_key(operation: &output::Operation) -> Option<(OperationKind, &str)> {
+    match operation {
+        output::Operation { rename: Some(rename), .. } => Some((OperationKind::Rename, &rename.delivery_option_handle)),
+        output::Operation { hide: Some(hide), .. } => Some((OperationKind::Hide, &hide.delivery_option_handle)),
+        output::Operation { move_: Some(move_), .. } => Some((OperationKind::Move, &move_.delivery_option_handle)),
+        _ => None
+    }
+}
+
+// Get the configuration from the metafield on your function owner, or None when there's no usable
+// configuration, logging what was missing
+pub(crate) fn safe_config(input: &input::ResponseData) -> Option<Configuration> {
+    let config_override = config_override();
+    let value = match (&config_override, &input.delivery_customization.metafield) {
+        (Some(value), _) => {
+            log(Level::Warn, "Using the configuration from the environment instead of the metafield.", &[
+                ("variable", json!(CONFIG_OVERRIDE_VARIABLE)),
+            ]);
+            value
+        }
+        (None, Some(input::InputDeliveryCustomizationMetafield { value })) => value,
+        (None, None) => {
+            log(Level::Info, "No configuration metafield, no need to customize delivery options.", &[
+                ("field", json!("deliveryCustomization.metafield")),
+            ]);
+            return None;
+        }
+    };
+    match Configuration::from_str(value) {
+        Ok(config) => match config.validate() {
+            Ok(()) => Some(config),
+            Err(errors) => {
+                log(Level::Error, "Invalid configuration value in metafield", &[
+                    ("errors", json!(errors)),
+                ]);
+                None
+            }
+        },
+        // A malformed configuration shouldn't break checkout, so leave the delivery options as they are
+        Err(error) => {
+            log(Level::Error, "Unable to parse configuration value from metafield", &[
+                ("error", json!(error.to_string())),
+            ]);
+            None
+        }
     }
 }
 
 #[shopify_function]
-fn function(input: input::ResponseData) -> Result<output::FunctionResult> {
-    let no_changes = output::FunctionResult { operations: vec![] };
+pub(crate) fn function(input: input::ResponseData) -> Result<output::FunctionResult> {
+    let result = customize(&input);
+    // Every run reports how many operations of each kind it produced, including runs that change nothing
+    let kinds = result.operations.iter().filter_map(operation_key).map(|(kind, _)| kind);
+    metric("operations", &logging::operation_counts(kinds));
+
+    // The shopify_function crate serializes your function result and writes it to STDOUT
+    Ok(result)
+}
 
-    // Get the configuration from the metafield on your function owner
-    let config = match input.delivery_customization.metafield {
-        Some(input::InputDeliveryCustomizationMetafield { value }) =>
-            Configuration::from_str(&value),
-        None => return Ok(no_changes),
+// Parse the configuration and customize the delivery options with it
+fn customize(input: &input::ResponseData) -> output::FunctionResult {
+    // Carts without delivery groups, like digital-only carts, have nothing to customize, so don't even
+    // parse the configuration
+    if input.cart.delivery_groups.is_empty() {
+        log(Level::Debug, "Cart has no delivery groups, no need to customize delivery options.", &[]);
+        return output::FunctionResult { operations: vec![] };
+    }
+    // Reading the clock is cheap, so the parse is always timed but only reported when profiling
+    let started = Instant::now();
+    let config = match safe_config(input) {
+        Some(config) => config,
+        None => return output::FunctionResult { operations: vec![] },
     };
+    if !config.enabled {
+        log(Level::Info, "Customization is disabled, no need to customize delivery options.", &[]);
+        return output::FunctionResult { operations: vec![] };
+    }
+
+    let mut profiler = Profiler::since(started, config.profile);
+    profiler.phase("parse");
+
+    apply_profiled(&config, input, profiler)
+}
 
-    let to_rename = input.cart.delivery_groups
+// Decide how to customize the delivery options for a parsed configuration, without any of the function's I/O.
+// Only tests, benchmarks and the evaluate tool start without a parse phase.
+#[cfg_attr(not(test), allow(dead_code))]
+pub(crate) fn apply(config: &Configuration, input: &input::ResponseData) -> output::FunctionResult {
+    apply_profiled(config, input, Profiler::start(config.profile))
+}
+
+// Like `apply`, timing the filter and build phases with a profiler that may already have timed the parse
+fn apply_profiled(config: &Configuration, input: &input::ResponseData, mut profiler: Profiler) -> output::FunctionResult {
+    let no_changes = output::FunctionResult { operations: vec![] };
+
+    // Mirror the payment customization's cart total gate
+    if let Some(min_cart_total) = config.min_cart_total {
+        match money::parse_decimal(&input.cart.cost.total_amount.amount) {
+            Some(cart_total) if cart_total >= min_cart_total => {}
+            Some(cart_total) => {
+                log(Level::Info, "Cart total is not high enough, no need to customize delivery options.", &[
+                    ("cart_total", json!(cart_total)),
+                    ("threshold", json!(min_cart_total)),
+                ]);
+                return no_changes;
+            }
+            None => {
+                log(Level::Warn, "Unable to parse cart total, no need to customize delivery options.", &[
+                    ("cart_total", json!(input.cart.cost.total_amount.amount)),
+                ]);
+                return no_changes;
+            }
+        }
+    }
+
+    let cart = CartContext::new(&input.cart);
+    if let Some(min_distinct_items) = config.min_distinct_items {
+        if cart.distinct_items < min_distinct_items {
+            log(Level::Info, "Cart doesn't have enough distinct items, no need to customize delivery options.", &[
+                ("distinct_items", json!(cart.distinct_items)),
+                ("threshold", json!(min_distinct_items)),
+            ]);
+            return no_changes;
+        }
+    }
+    let language = shopify_config::enum_value(&input.localization.language.iso_code);
+    let cart_total = money::parse_decimal(&input.cart.cost.total_amount.amount);
+    // An empty or missing attribute keeps the configured messages
+    let message_override = config.attribute_override_key.as_deref()
+        .and_then(|key| cart.attributes.get(key).copied())
+        .filter(|value| !value.is_empty());
+    let matched: Vec<_> = input.cart.delivery_groups
         .iter()
-        // Filter for delivery groups with a shipping address containing the affected state or province
-        .filter(|group| {
-            let postal_code = group.delivery_address.as_ref()
-                .and_then(|address| address.zip.as_ref());
-            match postal_code {
-                Some(code) => code == &config.zip,
-                None => false
+        // Find the rules matching the cart and each delivery group's shipping address, falling back to
+        // the unknown address rule for groups without an address or zip
+        .enumerate()
+        .filter_map(|(group_index, group)| {
+            let zip = group.delivery_address.as_ref()
+                .and_then(|address| address.zip.as_deref())
+                .map(shopify_config::normalize_whitespace);
+            let mut rules = config.matching_rules(group.delivery_address.as_ref(), &cart);
+            if rules.is_empty() && zip.is_none() {
+                rules.extend(config.unknown_address_rule.as_ref());
             }
+            // Free delivery options get the free shipping label even without a matching rule
+            (!rules.is_empty() || config.free_shipping_label.is_some()).then_some((group_index, group, rules, zip))
         })
-        // Collect the delivery options from these groups
-        .flat_map(|group| &group.delivery_options)
-        // Construct a rename operation for each, adding the message to the option title
-        .map(|option| output::RenameOperation {
-            delivery_option_handle: option.handle.to_string(),
-            title: match &option.title {
-                Some(title) => format!("{} - {}", title, config.message),
-                None => config.message.to_string()
-            }
+        .collect();
+    profiler.phase("filter");
+
+    let mut seen = HashSet::new();
+    let mut operations: Vec<_> = matched
+        .into_iter()
+        // Construct the operations for each delivery option according to the matching rules' modes
+        .flat_map(|(group_index, group, rules, zip)| {
+            let context = GroupContext {
+                zip: zip.as_deref(),
+                language: language.as_deref(),
+                message_override,
+                cart_total,
+                option_count: group.delivery_options.len(),
+                group_index
+            };
+            let free_shipping_label = config.free_shipping_label.as_deref();
+            group.delivery_options.iter()
+                .flat_map(|option| option_operations(option, &rules, free_shipping_label, &context))
+                .collect::<Vec<_>>()
+        })
+        // A delivery option shared by several groups in a split shipment only gets its first operations
+        .filter(|(_, operation)| {
+            operation_key(operation).is_some_and(|(kind, handle)| seen.insert((kind, handle.to_string())))
         })
-        // Wrap with an Operation
-        .map(|rename| output::Operation {
-            rename: Some(rename),
-            hide: None,
-            move_: None
+        // Drop the operations the store's runtime doesn't support rather than having it reject them all
+        .filter(|(_, operation)| {
+            operation_key(operation).is_some_and(|(kind, handle)| {
+                let supported = config.supported_operations.contains(&kind);
+                if !supported {
+                    log(Level::Warn, "Operation is not supported, dropping it.", &[
+                        ("kind", json!(kind)),
+                        ("delivery_option_handle", json!(handle)),
+                    ]);
+                }
+                supported
+            })
         })
         .collect();
 
-    // The shopify_function crate serializes your function result and writes it to STDOUT
-    Ok(output::FunctionResult { operations: to_rename })
+    if operations.len() > config.max_operations {
+        log(Level::Warn, "Too many operations, dropping the rest.", &[
+            ("dropped", json!(operations.len() - config.max_operations)),
+            ("max_operations", json!(config.max_operations)),
+        ]);
+        operations.truncate(config.max_operations);
+    }
+    // The order the groups and rules produced them in isn't meaningful outside of moves, so sort them to
+    // keep the output the same for the same operations
+    sort_operations(&mut operations, |(_, operation)| operation_key(operation));
+    profiler.phase("build");
+
+    if let Some(fields) = profiler.fields() {
+        log(Level::Info, "Profiled the run.", &fields);
+    }
+
+    // Only the operations left after de-duplication, the unsupported kinds and the limit are attributed to
+    // their rules, including a dry run's
+    for (rules, operation) in &operations {
+        rules.iter().for_each(|rule| log_rule_operation(rule, operation));
+    }
+    let operations: Vec<_> = operations.into_iter().map(|(_, operation)| operation).collect();
+
+    if config.dry_run {
+        log(Level::Info, "Dry run, not customizing delivery options.", &[
+            ("operations", json!(operations)),
+        ]);
+        return no_changes;
+    }
+
+    output::FunctionResult { operations }
 }
 
 #[cfg(test)]
@@ -7,14 +7,2288 @@ fn test_result_contains_no_operations() -> Result<()> {
         function,
         r#"
             {
+                "cart": {
+                    "cost": { "totalAmount": { "amount": "0.0" } },
+                    "lines": [],
+                    "deliveryGroups": []
+                },
+                "localization": { "language": { "isoCode": "EN" } },
                 "deliveryCustomization": {
                     "metafield": null
                 }
             }
         "#,
     )?;
-    let expected = crate::output::FunctionResult { operations: vec![] };
+    let expected = output::FunctionResult { operations: vec![] };
 
     assert_eq!(result, expected);
     Ok(())
 }
+
+#[test]
+fn test_cart_without_delivery_groups_skips_parsing_the_configuration() -> Result<()> {
+    let result = run_function_with_input(
+        function,
+        r#"
+            {
+                "cart": {
+                    "cost": { "totalAmount": { "amount": "0.0" } },
+                    "lines": [],
+                    "deliveryGroups": []
+                },
+                "localization": { "language": { "isoCode": "EN" } },
+                "deliveryCustomization": {
+                    "metafield": { "value": "not a configuration" }
+                }
+            }
+        "#,
+    )?;
+
+    assert_eq!(result, output::FunctionResult { operations: vec![] });
+    // The invalid configuration isn't even parsed
+    assert_eq!(
+        take_logs(),
+        vec![json!({"level": "debug", "message": "Cart has no delivery groups, no need to customize delivery options."})]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_renames_options_for_any_configured_zip() -> Result<()> {
+    let result = run_function_with_input(
+        function,
+        r#"
+            {
+                "cart": {
+                    "cost": { "totalAmount": { "amount": "0.0" } },
+                    "lines": [],
+                    "deliveryGroups": [
+                        {
+                            "deliveryAddress": { "zip": "99501" },
+                            "deliveryOptions": [{ "handle": "standard", "title": "Standard", "cost": { "amount": "5.0" } }]
+                        },
+                        {
+                            "deliveryAddress": { "zip": "96701" },
+                            "deliveryOptions": [{ "handle": "express", "title": "Express", "cost": { "amount": "5.0" } }]
+                        },
+                        {
+                            "deliveryAddress": { "zip": "10001" },
+                            "deliveryOptions": [{ "handle": "economy", "title": "Economy", "cost": { "amount": "5.0" } }]
+                        }
+                    ]
+                },
+                "localization": { "language": { "isoCode": "EN" } },
+                "deliveryCustomization": {
+                    "metafield": {
+                        "value": "{\"zips\": [\"99501\", \"96701\"], \"message\": \"Remote area\"}"
+                    }
+                }
+            }
+        "#,
+    )?;
+    let expected = output::FunctionResult {
+        operations: vec![
+            output::Operation {
+                rename: Some(output::RenameOperation {
+                    delivery_option_handle: "express".to_string(),
+                    title: "Express - Remote area".to_string(),
+                }),
+                hide: None,
+                move_: None,
+            },
+            output::Operation {
+                rename: Some(output::RenameOperation {
+                    delivery_option_handle: "standard".to_string(),
+                    title: "Standard - Remote area".to_string(),
+                }),
+                hide: None,
+                move_: None,
+            },
+        ],
+    };
+
+    assert_eq!(result, expected);
+    Ok(())
+}
+
+#[test]
+fn test_single_zip_configuration_is_still_supported() -> Result<()> {
+    let result = run_function_with_input(
+        function,
+        r#"
+            {
+                "cart": {
+                    "cost": { "totalAmount": { "amount": "0.0" } },
+                    "lines": [],
+                    "deliveryGroups": [
+                        {
+                            "deliveryAddress": { "zip": "99501" },
+                            "deliveryOptions": [{ "handle": "standard", "title": null, "cost": { "amount": "5.0" } }]
+                        }
+                    ]
+                },
+                "localization": { "language": { "isoCode": "EN" } },
+                "deliveryCustomization": {
+                    "metafield": {
+                        "value": "{\"zip\": \"99501\", \"message\": \"Remote area\"}"
+                    }
+                }
+            }
+        "#,
+    )?;
+    let expected = output::FunctionResult {
+        operations: vec![output::Operation {
+            rename: Some(output::RenameOperation {
+                delivery_option_handle: "standard".to_string(),
+                title: "Remote area".to_string(),
+            }),
+            hide: None,
+            move_: None,
+        }],
+    };
+
+    assert_eq!(result, expected);
+    Ok(())
+}
+
+#[test]
+fn test_empty_or_missing_zips_match_nothing() -> Result<()> {
+    for config in [
+        r#"{\"zip\": \"\", \"message\": \"Remote area\"}"#,
+        r#"{\"zips\": [], \"message\": \"Remote area\"}"#,
+        r#"{\"message\": \"Remote area\"}"#,
+    ] {
+        let result = run_with_zip("", config)?;
+        let expected = output::FunctionResult { operations: vec![] };
+
+        assert_eq!(result, expected);
+    }
+    Ok(())
+}
+
+// A delivery input with one group at zip 90210 offering a standard option, without a configuration
+fn base_input() -> serde_json::Value {
+    json!({
+        "cart": {
+            "cost": { "totalAmount": { "amount": "0.0" } },
+            "lines": [],
+            "deliveryGroups": [
+                {
+                    "deliveryAddress": { "zip": "90210" },
+                    "deliveryOptions": [{ "handle": "standard", "title": "Standard", "cost": { "amount": "5.0" } }]
+                }
+            ]
+        },
+        "localization": { "language": { "isoCode": "EN" } },
+        "deliveryCustomization": { "metafield": null }
+    })
+}
+
+// Run the function with `input` and the given escaped `config` as the metafield value
+fn run_with_input(mut input: serde_json::Value, config: &str) -> Result<output::FunctionResult> {
+    let value: String = serde_json::from_str(&format!("\"{}\"", config))?;
+    input["deliveryCustomization"]["metafield"] = json!({ "value": value });
+    run_function_with_input(function, &input.to_string())
+}
+
+fn run_with_zip(zip: &str, config: &str) -> Result<output::FunctionResult> {
+    let mut input = base_input();
+    input["cart"]["deliveryGroups"][0]["deliveryAddress"]["zip"] = json!(zip);
+    run_with_input(input, config)
+}
+
+fn renamed_standard(title: &str) -> output::FunctionResult {
+    output::FunctionResult {
+        operations: vec![output::Operation {
+            rename: Some(output::RenameOperation {
+                delivery_option_handle: "standard".to_string(),
+                title: title.to_string(),
+            }),
+            hide: None,
+            move_: None,
+        }],
+    }
+}
+
+#[test]
+fn test_zip_prefix_hit_renames_options() -> Result<()> {
+    let result = run_with_zip(
+        "SW1A 1AA",
+        r#"{\"zipPrefix\": \"SW1\", \"message\": \"Central London\"}"#,
+    )?;
+
+    assert_eq!(result, renamed_standard("Standard - Central London"));
+    Ok(())
+}
+
+#[test]
+fn test_zip_prefix_miss_does_not_rename() -> Result<()> {
+    let result = run_with_zip(
+        "SE1 7PB",
+        r#"{\"zipPrefix\": \"SW1\", \"message\": \"Central London\"}"#,
+    )?;
+
+    assert_eq!(result, output::FunctionResult { operations: vec![] });
+    Ok(())
+}
+
+#[test]
+fn test_zip_prefix_and_zips_are_combined() -> Result<()> {
+    let config = r#"{\"zips\": [\"E1 6AN\"], \"zipPrefix\": \"SW1\", \"message\": \"London\"}"#;
+
+    assert_eq!(run_with_zip("E1 6AN", config)?, renamed_standard("Standard - London"));
+    assert_eq!(run_with_zip("SW1A 1AA", config)?, renamed_standard("Standard - London"));
+    Ok(())
+}
+
+#[test]
+fn test_empty_zip_prefix_matches_nothing() -> Result<()> {
+    let result = run_with_zip(
+        "SW1A 1AA",
+        r#"{\"zipPrefix\": \"\", \"message\": \"Central London\"}"#,
+    )?;
+
+    assert_eq!(result, output::FunctionResult { operations: vec![] });
+    Ok(())
+}
+
+#[test]
+fn test_zip_range_matches_inclusive_bounds() -> Result<()> {
+    let config = r#"{\"zipRange\": [\"90001\", \"90210\"], \"message\": \"Metro\"}"#;
+
+    assert_eq!(run_with_zip("90100", config)?, renamed_standard("Standard - Metro"));
+    assert_eq!(run_with_zip("90001", config)?, renamed_standard("Standard - Metro"));
+    assert_eq!(run_with_zip("90210", config)?, renamed_standard("Standard - Metro"));
+    Ok(())
+}
+
+#[test]
+fn test_zip_range_skips_out_of_range_zips() -> Result<()> {
+    let config = r#"{\"zipRange\": [\"90001\", \"90210\"], \"message\": \"Metro\"}"#;
+    let no_changes = output::FunctionResult { operations: vec![] };
+
+    assert_eq!(run_with_zip("90000", config)?, no_changes);
+    assert_eq!(run_with_zip("90211", config)?, no_changes);
+    Ok(())
+}
+
+#[test]
+fn test_zip_range_skips_non_numeric_zips() -> Result<()> {
+    let config = r#"{\"zipRange\": [\"90001\", \"90210\"], \"message\": \"Metro\"}"#;
+
+    assert_eq!(
+        run_with_zip("K1A 0B1", config)?,
+        output::FunctionResult { operations: vec![] }
+    );
+    Ok(())
+}
+
+#[test]
+fn test_inverted_zip_range_is_rejected() {
+    let error = serde_json::from_str::<Configuration>(
+        r#"{"zipRange": ["90210", "90001"], "message": "Metro"}"#,
+    )
+    .err()
+    .expect("an inverted zip range should not parse");
+
+    assert!(error.to_string().contains("zip range is inverted"));
+}
+
+fn run_with_address(address: &str, config: &str) -> Result<output::FunctionResult> {
+    let mut input = base_input();
+    input["cart"]["deliveryGroups"][0]["deliveryAddress"] = serde_json::from_str(address)?;
+    run_with_input(input, config)
+}
+
+#[test]
+fn test_country_codes_and_zips_must_both_match() -> Result<()> {
+    let config = r#"{\"zips\": [\"90210\"], \"countryCodes\": [\"US\"], \"message\": \"Domestic\"}"#;
+    let no_changes = output::FunctionResult { operations: vec![] };
+
+    assert_eq!(
+        run_with_address(r#"{ "zip": "90210", "countryCode": "US" }"#, config)?,
+        renamed_standard("Standard - Domestic")
+    );
+    assert_eq!(run_with_address(r#"{ "zip": "90210", "countryCode": "MX" }"#, config)?, no_changes);
+    assert_eq!(run_with_address(r#"{ "zip": "10001", "countryCode": "US" }"#, config)?, no_changes);
+    Ok(())
+}
+
+#[test]
+fn test_country_codes_alone_match_any_zip_in_country() -> Result<()> {
+    let config = r#"{\"countryCodes\": [\"CA\"], \"message\": \"Ships from Canada\"}"#;
+
+    assert_eq!(
+        run_with_address(r#"{ "zip": "K1A 0B1", "countryCode": "CA" }"#, config)?,
+        renamed_standard("Standard - Ships from Canada")
+    );
+    assert_eq!(
+        run_with_address(r#"{ "zip": "90210", "countryCode": "US" }"#, config)?,
+        output::FunctionResult { operations: vec![] }
+    );
+    Ok(())
+}
+
+#[test]
+fn test_empty_country_codes_match_any_country() -> Result<()> {
+    let config = r#"{\"zips\": [\"90210\"], \"message\": \"Remote area\"}"#;
+
+    assert_eq!(
+        run_with_address(r#"{ "zip": "90210", "countryCode": "MX" }"#, config)?,
+        renamed_standard("Standard - Remote area")
+    );
+    Ok(())
+}
+
+#[test]
+fn test_provinces_match_case_insensitively() -> Result<()> {
+    let config = r#"{\"provinces\": [\"bc\"], \"message\": \"Remote area surcharge\"}"#;
+
+    assert_eq!(
+        run_with_address(r#"{ "zip": "V0N 1B4", "provinceCode": "BC" }"#, config)?,
+        renamed_standard("Standard - Remote area surcharge")
+    );
+    Ok(())
+}
+
+#[test]
+fn test_group_without_province_code_is_skipped() -> Result<()> {
+    let config = r#"{\"provinces\": [\"BC\"], \"message\": \"Remote area surcharge\"}"#;
+
+    assert_eq!(
+        run_with_address(r#"{ "zip": "V0N 1B4", "provinceCode": null }"#, config)?,
+        output::FunctionResult { operations: vec![] }
+    );
+    Ok(())
+}
+
+fn run_with_title(title: &str, config: &str) -> Result<output::FunctionResult> {
+    let mut input = base_input();
+    input["cart"]["deliveryGroups"][0]["deliveryOptions"][0]["title"] = serde_json::from_str(title)?;
+    run_with_input(input, config)
+}
+
+#[test]
+fn test_message_position_prefix() -> Result<()> {
+    let config = r#"{\"zip\": \"90210\", \"message\": \"Delayed\", \"messagePosition\": \"prefix\"}"#;
+
+    assert_eq!(run_with_title(r#""Standard""#, config)?, renamed_standard("Delayed - Standard"));
+    assert_eq!(run_with_title("null", config)?, renamed_standard("Delayed"));
+    Ok(())
+}
+
+#[test]
+fn test_message_position_suffix() -> Result<()> {
+    let config = r#"{\"zip\": \"90210\", \"message\": \"Delayed\", \"messagePosition\": \"suffix\"}"#;
+
+    assert_eq!(run_with_title(r#""Standard""#, config)?, renamed_standard("Standard - Delayed"));
+    assert_eq!(run_with_title("null", config)?, renamed_standard("Delayed"));
+    Ok(())
+}
+
+#[test]
+fn test_default_separator() -> Result<()> {
+    let config = r#"{\"zip\": \"90210\", \"message\": \"Delayed\"}"#;
+
+    assert_eq!(run_with_title(r#""Standard""#, config)?, renamed_standard("Standard - Delayed"));
+    Ok(())
+}
+
+#[test]
+fn test_custom_separator() -> Result<()> {
+    let config = r#"{\"zip\": \"90210\", \"message\": \"Delayed\", \"separator\": \" | \"}"#;
+
+    assert_eq!(run_with_title(r#""Standard""#, config)?, renamed_standard("Standard | Delayed"));
+    Ok(())
+}
+
+#[test]
+fn test_empty_separator_concatenates() -> Result<()> {
+    let config = r#"{\"zip\": \"90210\", \"message\": \"(Delayed)\", \"separator\": \"\"}"#;
+
+    assert_eq!(run_with_title(r#""Standard""#, config)?, renamed_standard("Standard(Delayed)"));
+    Ok(())
+}
+
+#[test]
+fn test_message_placeholders_are_substituted() -> Result<()> {
+    let config = r#"{\"zip\": \"90210\", \"message\": \"{title} to {zip} may be delayed\", \"separator\": \": \"}"#;
+
+    assert_eq!(
+        run_with_title(r#""Standard""#, config)?,
+        renamed_standard("Standard: Standard to 90210 may be delayed")
+    );
+    Ok(())
+}
+
+#[test]
+fn test_unknown_placeholders_are_left_untouched() -> Result<()> {
+    let config = r#"{\"zip\": \"90210\", \"message\": \"{carrier} to {zip} {\"}"#;
+
+    assert_eq!(
+        run_with_title(r#""Standard""#, config)?,
+        renamed_standard("Standard - {carrier} to 90210 {")
+    );
+    Ok(())
+}
+
+#[test]
+fn test_message_without_placeholders_is_unchanged() -> Result<()> {
+    let config = r#"{\"zip\": \"90210\", \"message\": \"May be delayed\"}"#;
+
+    assert_eq!(run_with_title(r#""Standard""#, config)?, renamed_standard("Standard - May be delayed"));
+    Ok(())
+}
+
+#[test]
+fn test_rename_mode_produces_rename_operations() -> Result<()> {
+    let config = r#"{\"zip\": \"90210\", \"message\": \"Delayed\", \"mode\": \"rename\"}"#;
+
+    assert_eq!(run_with_zip("90210", config)?, renamed_standard("Standard - Delayed"));
+    Ok(())
+}
+
+#[test]
+fn test_hide_mode_produces_hide_operations() -> Result<()> {
+    let config = r#"{\"zip\": \"90210\", \"mode\": \"hide\"}"#;
+    let expected = output::FunctionResult {
+        operations: vec![output::Operation {
+            rename: None,
+            hide: Some(output::HideOperation {
+                delivery_option_handle: "standard".to_string(),
+            }),
+            move_: None,
+        }],
+    };
+
+    assert_eq!(run_with_zip("90210", config)?, expected);
+    Ok(())
+}
+
+fn moved_standard(index: i64) -> output::FunctionResult {
+    output::FunctionResult {
+        operations: vec![output::Operation {
+            rename: None,
+            hide: None,
+            move_: Some(output::MoveOperation {
+                delivery_option_handle: "standard".to_string(),
+                index,
+            }),
+        }],
+    }
+}
+
+#[test]
+fn test_move_mode_produces_move_operations() -> Result<()> {
+    let config = r#"{\"zip\": \"90210\", \"mode\": {\"move\": {\"index\": 2}}}"#;
+
+    assert_eq!(run_with_zip("90210", config)?, moved_standard(2));
+    Ok(())
+}
+
+#[test]
+fn test_move_mode_clamps_negative_index_to_top() -> Result<()> {
+    let config = r#"{\"zip\": \"90210\", \"mode\": {\"move\": {\"index\": -3}}}"#;
+
+    assert_eq!(run_with_zip("90210", config)?, moved_standard(0));
+    Ok(())
+}
+
+// The structured log entries written by the current test, parsed back from JSON
+fn take_logs() -> Vec<serde_json::Value> {
+    LOGS.with(|logs| logs.take())
+        .iter()
+        .map(|entry| serde_json::from_str(entry).expect("log entries should be JSON"))
+        .collect()
+}
+
+// The logged entries other than the ones naming the rule behind each operation
+fn take_logs_without_rule_operations() -> Vec<serde_json::Value> {
+    take_logs().into_iter().filter(|entry| entry["message"] != "Rule produced an operation.").collect()
+}
+
+fn take_metrics() -> Vec<serde_json::Value> {
+    METRICS.with(|metrics| metrics.take())
+        .iter()
+        .map(|entry| serde_json::from_str(entry).expect("metric entries should be JSON"))
+        .collect()
+}
+
+#[test]
+fn test_invalid_configuration_returns_no_changes_and_logs() -> Result<()> {
+    let result = run_with_zip("90210", r#"{\"zip\": \"90210\", \"message\":"#)?;
+
+    assert_eq!(result, output::FunctionResult { operations: vec![] });
+    let logs = take_logs();
+    assert_eq!(logs.len(), 1);
+    assert_eq!(logs[0]["level"], "error");
+    assert_eq!(logs[0]["message"], "Unable to parse configuration value from metafield");
+    assert!(logs[0]["error"].is_string());
+    Ok(())
+}
+
+fn run_with_cart_total(amount: &str, config: &str) -> Result<output::FunctionResult> {
+    let mut input = base_input();
+    input["cart"]["cost"]["totalAmount"]["amount"] = json!(amount);
+    run_with_input(input, config)
+}
+
+#[test]
+fn test_cart_total_below_minimum_skips_renames() -> Result<()> {
+    let config = r#"{\"zip\": \"90210\", \"message\": \"May be delayed\", \"minCartTotal\": \"250.00\"}"#;
+
+    assert_eq!(
+        run_with_cart_total("249.99", config)?,
+        output::FunctionResult { operations: vec![] }
+    );
+    Ok(())
+}
+
+#[test]
+fn test_cart_total_above_minimum_applies_renames() -> Result<()> {
+    let config = r#"{\"zip\": \"90210\", \"message\": \"May be delayed\", \"minCartTotal\": 250}"#;
+
+    assert_eq!(run_with_cart_total("250.00", config)?, renamed_standard("Standard - May be delayed"));
+    assert_eq!(run_with_cart_total("900.00", config)?, renamed_standard("Standard - May be delayed"));
+    Ok(())
+}
+
+fn run_with_zips(zips: &[&str], config: &str) -> Result<output::FunctionResult> {
+    run_with_input(input_json_with_zips(zips, "0.0"), config)
+}
+
+fn renamed(handle: &str, title: &str) -> output::Operation {
+    output::Operation::rename(output::RenameOperation {
+        delivery_option_handle: handle.to_string(),
+        title: title.to_string(),
+    })
+}
+
+#[test]
+fn test_multiple_rules_apply_to_different_groups() -> Result<()> {
+    let config = concat!(
+        r#"{\"rules\": ["#,
+        r#"{\"zips\": [\"99501\"], \"message\": \"Remote\"},"#,
+        r#"{\"zips\": [\"96701\"], \"message\": \"Island\"}"#,
+        r#"]}"#
+    );
+    let result = run_with_zips(&["99501", "96701", "10001"], config)?;
+    let expected = output::FunctionResult {
+        operations: vec![
+            renamed("standard-96701", "Standard - Island"),
+            renamed("standard-99501", "Standard - Remote"),
+        ],
+    };
+
+    assert_eq!(result, expected);
+    Ok(())
+}
+
+#[test]
+fn test_first_matching_rule_wins_by_default() -> Result<()> {
+    let config = concat!(
+        r#"{\"rules\": ["#,
+        r#"{\"zipPrefix\": \"99\", \"message\": \"Remote\"},"#,
+        r#"{\"zips\": [\"99501\"], \"message\": \"Anchorage\"}"#,
+        r#"]}"#
+    );
+
+    assert_eq!(
+        run_with_zips(&["99501"], config)?,
+        output::FunctionResult { operations: vec![renamed("standard-99501", "Standard - Remote")] }
+    );
+    Ok(())
+}
+
+#[test]
+fn test_all_matching_rules_apply_when_selected() -> Result<()> {
+    let config = concat!(
+        r#"{\"ruleSelection\": \"all\", \"rules\": ["#,
+        r#"{\"zipPrefix\": \"99\", \"message\": \"Remote\"},"#,
+        r#"{\"zips\": [\"99501\"], \"message\": \"Anchorage\"}"#,
+        r#"]}"#
+    );
+
+    assert_eq!(
+        run_with_zips(&["99501"], config)?,
+        output::FunctionResult {
+            operations: vec![renamed("standard-99501", "Standard - Remote - Anchorage")]
+        }
+    );
+    Ok(())
+}
+
+#[test]
+fn test_cart_total_gate_logs_structured_context() -> Result<()> {
+    let config = r#"{\"zip\": \"90210\", \"message\": \"May be delayed\", \"minCartTotal\": \"250.00\"}"#;
+    run_with_cart_total("100.00", config)?;
+
+    assert_eq!(
+        take_logs(),
+        vec![json!({
+            "level": "info",
+            "message": "Cart total is not high enough, no need to customize delivery options.",
+            "cart_total": "100.00",
+            "threshold": "250.00"
+        })]
+    );
+    Ok(())
+}
+
+fn run_with_options(zip: &str, options: &str, config: &str) -> Result<output::FunctionResult> {
+    let mut input = base_input();
+    input["cart"]["deliveryGroups"][0]["deliveryAddress"]["zip"] = json!(zip);
+    input["cart"]["deliveryGroups"][0]["deliveryOptions"] = serde_json::from_str(options)?;
+    run_with_input(input, config)
+}
+
+fn hidden(handle: &str) -> output::Operation {
+    output::Operation::hide(output::HideOperation {
+        delivery_option_handle: handle.to_string(),
+    })
+}
+
+const STANDARD_AND_EXPRESS: &str = r#"[
+    { "handle": "standard", "title": "Standard", "cost": { "amount": "5.0" } },
+    { "handle": "express", "title": "Priority Express", "cost": { "amount": "5.0" } }
+]"#;
+
+#[test]
+fn test_title_contains_hides_matching_options() -> Result<()> {
+    let config = r#"{\"zip\": \"99501\", \"titleContains\": \"express\", \"mode\": \"hide\"}"#;
+
+    assert_eq!(
+        run_with_options("99501", STANDARD_AND_EXPRESS, config)?,
+        output::FunctionResult { operations: vec![hidden("express")] }
+    );
+    Ok(())
+}
+
+#[test]
+fn test_title_contains_miss_leaves_options_alone() -> Result<()> {
+    let config = r#"{\"zip\": \"99501\", \"titleContains\": \"Overnight\", \"mode\": \"hide\"}"#;
+
+    assert_eq!(
+        run_with_options("99501", STANDARD_AND_EXPRESS, config)?,
+        output::FunctionResult { operations: vec![] }
+    );
+    Ok(())
+}
+
+#[test]
+fn test_title_contains_requires_zip_match_too() -> Result<()> {
+    let config = r#"{\"zip\": \"99501\", \"titleContains\": \"Express\", \"mode\": \"hide\"}"#;
+
+    assert_eq!(
+        run_with_options("10001", STANDARD_AND_EXPRESS, config)?,
+        output::FunctionResult { operations: vec![] }
+    );
+    Ok(())
+}
+
+const CARRIER_CALCULATED: &str = r#"[
+    { "handle": "shopify-Standard-5.00", "title": "Standard", "cost": { "amount": "5.0" } },
+    { "handle": "shopify-Standard-7.50", "title": "Standard (Large items)", "cost": { "amount": "7.5" } },
+    { "handle": "shopify-Express-15.00", "title": "Express", "cost": { "amount": "15.0" } }
+]"#;
+
+#[test]
+fn test_handle_prefix_only_renames_options_with_matching_handles() -> Result<()> {
+    let config = r#"{\"zip\": \"99501\", \"handlePrefix\": \"shopify-Standard-\", \"message\": \"Ground\"}"#;
+    let expected = output::FunctionResult {
+        operations: vec![
+            renamed("shopify-Standard-5.00", "Standard - Ground"),
+            renamed("shopify-Standard-7.50", "Standard (Large items) - Ground"),
+        ],
+    };
+
+    assert_eq!(run_with_options("99501", CARRIER_CALCULATED, config)?, expected);
+    Ok(())
+}
+
+#[test]
+fn test_handle_prefix_miss_leaves_options_alone() -> Result<()> {
+    let config = r#"{\"zip\": \"99501\", \"handlePrefix\": \"shopify-Economy-\", \"message\": \"Ground\"}"#;
+
+    assert_eq!(
+        run_with_options("99501", CARRIER_CALCULATED, config)?,
+        output::FunctionResult { operations: vec![] }
+    );
+    Ok(())
+}
+
+#[test]
+fn test_option_messages_give_options_in_a_group_their_own_messages() -> Result<()> {
+    let config = r#"{\"zip\": \"99501\", \"message\": \"Delayed\", \"optionMessages\": {\"shopify-Express-15.00\": \"Arrives tomorrow\", \"Standard (Large items)\": \"Ships separately\"}}"#;
+    let expected = output::FunctionResult {
+        operations: vec![
+            renamed("shopify-Express-15.00", "Express - Arrives tomorrow"),
+            renamed("shopify-Standard-5.00", "Standard - Delayed"),
+            renamed("shopify-Standard-7.50", "Standard (Large items) - Ships separately"),
+        ],
+    };
+
+    assert_eq!(run_with_options("99501", CARRIER_CALCULATED, config)?, expected);
+    Ok(())
+}
+
+#[test]
+fn test_option_message_by_handle_wins_over_title() -> Result<()> {
+    let config = r#"{\"zip\": \"99501\", \"message\": \"Delayed\", \"optionMessages\": {\"Express\": \"By title\", \"shopify-Express-15.00\": \"By handle\"}}"#;
+    let result = run_with_options("99501", CARRIER_CALCULATED, config)?;
+
+    assert!(result.operations.contains(&renamed("shopify-Express-15.00", "Express - By handle")));
+    Ok(())
+}
+
+#[test]
+fn test_option_messages_alone_only_rename_their_options() -> Result<()> {
+    let config = r#"{\"zip\": \"99501\", \"optionMessages\": {\"Express\": \"Arrives tomorrow\"}}"#;
+    let expected = output::FunctionResult {
+        operations: vec![renamed("shopify-Express-15.00", "Express - Arrives tomorrow")],
+    };
+
+    assert_eq!(run_with_options("99501", CARRIER_CALCULATED, config)?, expected);
+    Ok(())
+}
+
+#[test]
+fn test_operation_counts_metric_matches_the_result() -> Result<()> {
+    let config = r#"{\"zip\": \"99501\", \"handlePrefix\": \"shopify-Standard-\", \"message\": \"Ground\", \"moveTo\": \"top\"}"#;
+    let result = run_with_options("99501", CARRIER_CALCULATED, config)?;
+    let mut counts = json!({"metric": "operations", "hide": 0, "move": 0, "rename": 0});
+    for (kind, _) in result.operations.iter().filter_map(operation_key) {
+        counts[kind.name()] = json!(counts[kind.name()].as_u64().unwrap() + 1);
+    }
+
+    assert_eq!(take_metrics(), vec![counts]);
+    assert_eq!(result.operations.len(), 4);
+    Ok(())
+}
+
+#[test]
+fn test_rule_ids_are_logged_with_the_operations_they_produce() -> Result<()> {
+    let config = r#"{\"ruleSelection\": \"all\", \"rules\": [{\"id\": \"ground\", \"zip\": \"99501\", \"handlePrefix\": \"shopify-Standard-\", \"message\": \"Ground\"}, {\"zip\": \"99501\", \"titleContains\": \"Express\", \"mode\": \"hide\"}]}"#;
+    run_with_options("99501", CARRIER_CALCULATED, config)?;
+
+    let logged: Vec<_> = take_logs()
+        .into_iter()
+        .filter(|entry| entry["message"] == "Rule produced an operation.")
+        .map(|entry| (entry["rule"].clone(), entry["kind"].clone(), entry["delivery_option_handle"].clone()))
+        .collect();
+    assert_eq!(logged, vec![
+        (json!("ground"), json!("rename"), json!("shopify-Standard-5.00")),
+        (json!("ground"), json!("rename"), json!("shopify-Standard-7.50")),
+        // Rules without an id are named by their position
+        (json!("rules[1]"), json!("hide"), json!("shopify-Express-15.00")),
+    ]);
+    Ok(())
+}
+
+#[test]
+fn test_rules_are_not_logged_for_dropped_operations() -> Result<()> {
+    let config = r#"{\"ruleSelection\": \"all\", \"supportedOperations\": [\"rename\", \"move\"], \"maxOperations\": 1, \"rules\": [{\"id\": \"ground\", \"zip\": \"99501\", \"handlePrefix\": \"shopify-Standard-\", \"message\": \"Ground\"}, {\"id\": \"no-express\", \"zip\": \"99501\", \"titleContains\": \"Express\", \"mode\": \"hide\"}]}"#;
+    let result = run_with_options("99501", CARRIER_CALCULATED, config)?;
+
+    assert_eq!(result.operations, vec![renamed("shopify-Standard-5.00", "Standard - Ground")]);
+    let logged: Vec<_> = take_logs()
+        .into_iter()
+        .filter(|entry| entry["message"] == "Rule produced an operation.")
+        .map(|entry| (entry["rule"].clone(), entry["delivery_option_handle"].clone()))
+        .collect();
+    // The hide isn't supported and the second rename is past the limit
+    assert_eq!(logged, vec![(json!("ground"), json!("shopify-Standard-5.00"))]);
+    Ok(())
+}
+
+fn run_with_lines(lines: &str, config: &str) -> Result<output::FunctionResult> {
+    let mut input = base_input();
+    input["cart"]["lines"] = serde_json::from_str(lines)?;
+    run_with_input(input, config)
+}
+
+const FRAGILE_LINES: &str = r#"[
+    {
+        "merchandise": {
+            "__typename": "ProductVariant",
+            "id": "gid://shopify/ProductVariant/11",
+            "product": { "id": "gid://shopify/Product/1" }
+        }
+    },
+    { "merchandise": { "__typename": "CustomProduct" } }
+]"#;
+
+#[test]
+fn test_product_ids_apply_when_cart_contains_product() -> Result<()> {
+    let config = r#"{\"zip\": \"90210\", \"message\": \"Fragile\", \"productIds\": [\"gid://shopify/Product/1\", \"gid://shopify/Product/2\"]}"#;
+
+    assert_eq!(run_with_lines(FRAGILE_LINES, config)?, renamed_standard("Standard - Fragile"));
+    Ok(())
+}
+
+#[test]
+fn test_product_ids_skip_when_cart_lacks_product() -> Result<()> {
+    let config = r#"{\"zip\": \"90210\", \"message\": \"Fragile\", \"productIds\": [\"gid://shopify/Product/2\"]}"#;
+
+    assert_eq!(
+        run_with_lines(FRAGILE_LINES, config)?,
+        output::FunctionResult { operations: vec![] }
+    );
+    Ok(())
+}
+
+#[test]
+fn test_empty_product_ids_do_not_gate() -> Result<()> {
+    let config = r#"{\"zip\": \"90210\", \"message\": \"Fragile\"}"#;
+
+    assert_eq!(run_with_lines("[]", config)?, renamed_standard("Standard - Fragile"));
+    Ok(())
+}
+
+#[test]
+fn test_exclude_zips_match_every_other_zip() -> Result<()> {
+    let config = r#"{\"excludeZips\": [\"90210\"], \"message\": \"Ships Free\"}"#;
+
+    let result = run_with_zips(&["90210", "10001", "60601"], config)?;
+    let expected = output::FunctionResult {
+        operations: vec![
+            renamed("standard-10001", "Standard - Ships Free"),
+            renamed("standard-60601", "Standard - Ships Free"),
+        ],
+    };
+
+    assert_eq!(result, expected);
+    Ok(())
+}
+
+#[test]
+fn test_zips_alone_remain_an_allow_list() -> Result<()> {
+    let config = r#"{\"zips\": [\"90210\", \"10001\"], \"message\": \"Ships Free\"}"#;
+
+    let result = run_with_zips(&["90210", "10001", "60601"], config)?;
+    let expected = output::FunctionResult {
+        operations: vec![
+            renamed("standard-10001", "Standard - Ships Free"),
+            renamed("standard-90210", "Standard - Ships Free"),
+        ],
+    };
+
+    assert_eq!(result, expected);
+    Ok(())
+}
+
+#[test]
+fn test_exclude_zips_win_over_allowed_zips() -> Result<()> {
+    let config = r#"{\"zipPrefix\": \"902\", \"excludeZips\": \"90210\", \"message\": \"Ships Free\"}"#;
+
+    let result = run_with_zips(&["90210", "90211", "10001"], config)?;
+    let expected = output::FunctionResult {
+        operations: vec![renamed("standard-90211", "Standard - Ships Free")],
+    };
+
+    assert_eq!(result, expected);
+    Ok(())
+}
+
+// Run the function against a recorded Shopify input in `tests/fixtures`
+fn run_fixture(name: &str) -> Result<output::FunctionResult> {
+    let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name);
+    let input = std::fs::read_to_string(&path)
+        .unwrap_or_else(|error| panic!("unable to read fixture {}: {}", path.display(), error));
+    run_function_with_input(function, &input)
+}
+
+#[test]
+fn test_fixture_renames_and_hides_options_by_zip() -> Result<()> {
+    let result = run_fixture("rename_and_hide_by_zip.json")?;
+    let expected = output::FunctionResult {
+        operations: vec![
+            renamed("a1f1b5d7c0e0f9e4c1b0a9d8e7f6a5b4-shipping", "Standard - Ships from Los Angeles"),
+            hidden("b2e2c6e8d1f1a0f5d2c1b0e9f8a7b6c5-shipping"),
+        ],
+    };
+
+    assert_eq!(result, expected);
+    Ok(())
+}
+
+// Compare the serialized result with `tests/snapshots/<name>.snap`. Run the tests with
+// `UPDATE_SNAPSHOTS=1` to write the current result as the new snapshot.
+fn assert_snapshot(name: &str, result: &output::FunctionResult) {
+    let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/snapshots")
+        .join(format!("{}.snap", name));
+    let actual = serde_json::to_string_pretty(result).expect("results should serialize") + "\n";
+    if std::env::var_os("UPDATE_SNAPSHOTS").is_some() {
+        std::fs::create_dir_all(path.parent().unwrap()).expect("unable to create the snapshots directory");
+        std::fs::write(&path, &actual).expect("unable to write the snapshot");
+        return;
+    }
+    let expected = std::fs::read_to_string(&path).unwrap_or_else(|error| {
+        panic!("unable to read snapshot {} ({}), run with UPDATE_SNAPSHOTS=1 to create it", path.display(), error)
+    });
+    assert_eq!(actual, expected, "result doesn't match snapshot {}", path.display());
+}
+
+#[test]
+fn test_fixture_snapshot() -> Result<()> {
+    let result = run_fixture("rename_and_hide_by_zip.json")?;
+
+    assert_snapshot("rename_and_hide_by_zip", &result);
+    Ok(())
+}
+
+#[test]
+fn test_unknown_address_is_skipped_without_default_message() -> Result<()> {
+    let config = r#"{\"zip\": \"90210\", \"message\": \"Ships Free\"}"#;
+
+    for address in ["null", "{}"] {
+        let result = run_with_address(address, config)?;
+
+        assert_eq!(result, output::FunctionResult { operations: vec![] });
+    }
+    Ok(())
+}
+
+#[test]
+fn test_unknown_address_gets_default_message() -> Result<()> {
+    let config = r#"{\"zip\": \"90210\", \"message\": \"Ships Free\", \"defaultMessageForUnknownAddress\": \"Enter your zip for delivery times\"}"#;
+
+    for address in ["null", "{}", r#"{ "countryCode": "US" }"#] {
+        let result = run_with_address(address, config)?;
+
+        assert_eq!(result, renamed_standard("Standard - Enter your zip for delivery times"));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_default_message_for_unknown_address_works_without_rules() -> Result<()> {
+    let config = r#"{\"defaultMessageForUnknownAddress\": \"Enter your zip\"}"#;
+
+    assert_eq!(run_with_address("null", config)?, renamed_standard("Standard - Enter your zip"));
+    assert_eq!(run_with_zip("90210", config)?, output::FunctionResult { operations: vec![] });
+    assert!(take_logs().iter().all(|log| log["level"] != "error"));
+    Ok(())
+}
+
+#[test]
+fn test_default_message_ignores_known_zips() -> Result<()> {
+    let config = r#"{\"zip\": \"90210\", \"message\": \"Ships Free\", \"defaultMessageForUnknownAddress\": \"Enter your zip\"}"#;
+
+    assert_eq!(run_with_zip("90210", config)?, renamed_standard("Standard - Ships Free"));
+    assert_eq!(run_with_zip("10001", config)?, output::FunctionResult { operations: vec![] });
+    Ok(())
+}
+
+#[test]
+fn test_rename_is_skipped_when_title_already_has_message() -> Result<()> {
+    let config = r#"{\"zip\": \"90210\", \"message\": \"Ships Free\"}"#;
+
+    let result = run_with_title(r#""Standard - Ships Free""#, config)?;
+
+    assert_eq!(result, output::FunctionResult { operations: vec![] });
+    Ok(())
+}
+
+#[test]
+fn test_rename_is_skipped_when_title_already_has_prefix_with_custom_separator() -> Result<()> {
+    let config = r#"{\"zip\": \"90210\", \"message\": \"Ships Free\", \"messagePosition\": \"prefix\", \"separator\": \": \"}"#;
+
+    assert_eq!(run_with_title(r#""Ships Free: Standard""#, config)?, output::FunctionResult { operations: vec![] });
+    assert_eq!(run_with_title(r#""Ships Free - Standard""#, config)?, renamed_standard("Ships Free: Ships Free - Standard"));
+    Ok(())
+}
+
+#[test]
+fn test_multi_byte_messages_are_kept_whole() -> Result<()> {
+    let suffix = r#"{\"zip\": \"90210\", \"message\": \"{zip}へ配送に遅れ\", \"separator\": \"・\"}"#;
+    let prefix = r#"{\"zip\": \"90210\", \"message\": \"توصيل متأخر\", \"messagePosition\": \"prefix\"}"#;
+
+    assert_eq!(run_with_title(r#""通常配送""#, suffix)?, renamed_standard("通常配送・90210へ配送に遅れ"));
+    assert_eq!(run_with_title(r#""عادي""#, prefix)?, renamed_standard("توصيل متأخر - عادي"));
+    Ok(())
+}
+
+#[test]
+fn test_rename_is_skipped_when_title_already_has_a_multi_byte_message() -> Result<()> {
+    let config = r#"{\"zip\": \"90210\", \"message\": \"配送遅延\", \"separator\": \"・\"}"#;
+
+    assert_eq!(run_with_title(r#""通常配送・配送遅延""#, config)?, output::FunctionResult { operations: vec![] });
+    assert_eq!(run_with_title(r#""通常配送遅延""#, config)?, renamed_standard("通常配送遅延・配送遅延"));
+    Ok(())
+}
+
+#[test]
+fn test_replace_title_replaces_the_whole_title_instead_of_appending() -> Result<()> {
+    let replace = r#"{\"zip\": \"90210\", \"message\": \"Delayed\", \"replaceTitle\": \"Economy (3–5 days)\"}"#;
+    let append = r#"{\"zip\": \"90210\", \"message\": \"Delayed\"}"#;
+
+    assert_eq!(run_with_title(r#""Standard""#, replace)?, renamed_standard("Economy (3–5 days)"));
+    assert_eq!(run_with_title("null", replace)?, renamed_standard("Economy (3–5 days)"));
+    assert_eq!(run_with_title(r#""Economy (3–5 days)""#, replace)?, output::FunctionResult { operations: vec![] });
+    assert_eq!(run_with_title(r#""Standard""#, append)?, renamed_standard("Standard - Delayed"));
+    Ok(())
+}
+
+#[test]
+fn test_replace_title_only_applies_when_renaming() {
+    let config = Configuration::from_str(r#"{"zip": "90210", "mode": "hide", "replaceTitle": ""}"#).unwrap();
+
+    assert_eq!(
+        config.validate(),
+        Err(vec![
+            "rules[0]: replaceTitle must not be empty".to_string(),
+            "rules[0]: replaceTitle only applies when renaming".to_string(),
+        ])
+    );
+}
+
+#[test]
+fn test_second_application_is_a_no_op() -> Result<()> {
+    let config = r#"{\"zip\": \"90210\", \"message\": \"Ships to {zip}\"}"#;
+
+    let first = run_with_title(r#""Standard""#, config)?;
+    let title = first.operations[0].rename.as_ref().map(|rename| rename.title.clone()).unwrap();
+    let second = run_with_title(&format!("{:?}", title), config)?;
+
+    assert_eq!(title, "Standard - Ships to 90210");
+    assert_eq!(second, output::FunctionResult { operations: vec![] });
+    Ok(())
+}
+
+#[test]
+fn test_zip_regex_matches_zips() -> Result<()> {
+    let config = r#"{\"zipRegex\": \"^9[0-4]\\\\d{3}$\", \"message\": \"West Coast\"}"#;
+
+    let result = run_with_zips(&["90210", "94105", "95014", "10001"], config)?;
+    let expected = output::FunctionResult {
+        operations: vec![
+            renamed("standard-90210", "Standard - West Coast"),
+            renamed("standard-94105", "Standard - West Coast"),
+        ],
+    };
+
+    assert_eq!(result, expected);
+    Ok(())
+}
+
+#[test]
+fn test_zip_regex_combines_with_zips() -> Result<()> {
+    let config = r#"{\"zips\": [\"90210\", \"10001\"], \"zipRegex\": \"^9\", \"message\": \"West Coast\"}"#;
+
+    let result = run_with_zips(&["90210", "94105", "10001"], config)?;
+
+    assert_eq!(result.operations, vec![renamed("standard-90210", "Standard - West Coast")]);
+    Ok(())
+}
+
+#[test]
+fn test_title_regex_filters_options() -> Result<()> {
+    let config = r#"{\"zip\": \"90210\", \"titleRegex\": \"Express$\", \"message\": \"Fast\"}"#;
+
+    let result = run_with_options("90210", STANDARD_AND_EXPRESS, config)?;
+
+    assert_eq!(result.operations, vec![renamed("express", "Priority Express - Fast")]);
+    Ok(())
+}
+
+#[test]
+fn test_invalid_regex_is_logged_and_ignored() -> Result<()> {
+    let config = r#"{\"zip\": \"90210\", \"titleRegex\": \"(Exp\", \"message\": \"Fast\"}"#;
+
+    let result = run_with_options("90210", STANDARD_AND_EXPRESS, config)?;
+
+    assert_eq!(
+        result.operations,
+        vec![renamed("express", "Priority Express - Fast"), renamed("standard", "Standard - Fast")]
+    );
+    let logs = take_logs_without_rule_operations();
+    assert_eq!(logs.len(), 1);
+    assert_eq!(logs[0]["level"], "warn");
+    assert_eq!(logs[0]["field"], "titleRegex");
+    assert_eq!(logs[0]["pattern"], "(Exp");
+    Ok(())
+}
+
+#[test]
+fn test_v1_configuration_is_migrated_to_current_version() {
+    let v1 = Configuration::from_str(r#"{"zip": "90210", "message": "Ships Free"}"#).unwrap();
+    let v2 = Configuration::from_str(r#"{"version": 2, "rules": [{"zips": ["90210"], "message": "Ships Free"}]}"#).unwrap();
+
+    assert_eq!(v1.version, CONFIGURATION_VERSION);
+    assert_eq!(v1.rules.len(), 1);
+    assert_eq!(v1.rules[0].zips, HashSet::from(["90210".to_string()]));
+    assert!(v1 == v2);
+}
+
+#[test]
+fn test_v2_configuration_does_not_accept_v1_keys() {
+    let v2 = Configuration::from_str(r#"{"version": 2, "zip": "90210", "message": "Ships Free"}"#).unwrap();
+
+    assert!(v2.rules[0].zips.is_empty());
+}
+
+#[test]
+fn test_unsupported_configuration_version_falls_back_to_defaults() -> Result<()> {
+    let config = r#"{\"version\": 3, \"zip\": \"90210\", \"message\": \"Ships Free\"}"#;
+
+    assert_eq!(run_with_zip("90210", config)?, output::FunctionResult { operations: vec![] });
+    let logs = take_logs();
+    assert_eq!(logs.len(), 1);
+    assert_eq!(logs[0]["level"], "warn");
+    assert_eq!(logs[0]["version"], 3);
+    Ok(())
+}
+
+#[test]
+fn test_condition_logic_all_requires_every_condition() -> Result<()> {
+    let config = r#"{\"zip\": \"90210\", \"countryCodes\": [\"CA\"], \"message\": \"Ships Free\"}"#;
+
+    let result = run_with_address(r#"{ "zip": "90210", "countryCode": "US" }"#, config)?;
+
+    assert_eq!(result, output::FunctionResult { operations: vec![] });
+    Ok(())
+}
+
+#[test]
+fn test_condition_logic_any_requires_one_condition() -> Result<()> {
+    let config = r#"{\"zip\": \"90210\", \"countryCodes\": [\"CA\"], \"message\": \"Ships Free\", \"conditionLogic\": \"any\"}"#;
+
+    let zip_only = run_with_address(r#"{ "zip": "90210", "countryCode": "US" }"#, config)?;
+    let country_only = run_with_address(r#"{ "zip": "10001", "countryCode": "CA" }"#, config)?;
+    let neither = run_with_address(r#"{ "zip": "10001", "countryCode": "US" }"#, config)?;
+
+    assert_eq!(zip_only, renamed_standard("Standard - Ships Free"));
+    assert_eq!(country_only, renamed_standard("Standard - Ships Free"));
+    assert_eq!(neither, output::FunctionResult { operations: vec![] });
+    Ok(())
+}
+
+#[test]
+fn test_condition_logic_any_includes_product_ids() -> Result<()> {
+    let config = r#"{\"zip\": \"10001\", \"productIds\": [\"gid://shopify/Product/1\"], \"message\": \"Fragile\", \"conditionLogic\": \"any\"}"#;
+
+    assert_eq!(run_with_lines(FRAGILE_LINES, config)?, renamed_standard("Standard - Fragile"));
+    Ok(())
+}
+
+#[test]
+fn test_dry_run_logs_operations_without_applying_them() -> Result<()> {
+    let config = r#"{\"zip\": \"90210\", \"message\": \"Ships Free\", \"dryRun\": true}"#;
+
+    let result = run_with_zip("90210", config)?;
+
+    assert_eq!(result, output::FunctionResult { operations: vec![] });
+    let logs = take_logs_without_rule_operations();
+    assert_eq!(logs.len(), 1);
+    assert_eq!(logs[0]["message"], "Dry run, not customizing delivery options.");
+    assert_eq!(
+        logs[0]["operations"],
+        serde_json::json!([{ "rename": { "deliveryOptionHandle": "standard", "title": "Standard - Ships Free" } }])
+    );
+    Ok(())
+}
+
+#[test]
+fn test_shared_delivery_option_handle_is_renamed_once() -> Result<()> {
+    let config = r#"{\"zips\": [\"90210\", \"10001\"], \"message\": \"Ships to {zip}\"}"#;
+    let mut input = base_input();
+    let mut group = input["cart"]["deliveryGroups"][0].clone();
+    group["deliveryAddress"]["zip"] = json!("10001");
+    input["cart"]["deliveryGroups"].as_array_mut().unwrap().push(group);
+
+    let result = run_with_input(input, config)?;
+
+    assert_eq!(result, renamed_standard("Standard - Ships to 90210"));
+    Ok(())
+}
+
+// A delivery input for calling `apply` directly, with one group per zip like `run_with_zips`
+fn input_with_zips(zips: &[&str], cart_total: &str) -> input::ResponseData {
+    serde_json::from_value(input_json_with_zips(zips, cart_total)).expect("the input should match the generated types")
+}
+
+// The base input with one group per zip, each with a standard option whose handle ends with the zip
+fn input_json_with_zips(zips: &[&str], cart_total: &str) -> serde_json::Value {
+    let mut input = base_input();
+    let group = input["cart"]["deliveryGroups"][0].take();
+    input["cart"]["deliveryGroups"] = zips
+        .iter()
+        .map(|zip| {
+            let mut group = group.clone();
+            group["deliveryAddress"]["zip"] = json!(zip);
+            group["deliveryOptions"][0]["handle"] = json!(format!("standard-{}", zip));
+            group
+        })
+        .collect();
+    input["cart"]["cost"]["totalAmount"]["amount"] = json!(cart_total);
+    input
+}
+
+#[test]
+fn test_apply_renames_matching_groups() {
+    let config = Configuration::from_str(r#"{"zips": ["90210"], "message": "Ships Free"}"#).unwrap();
+
+    let result = apply(&config, &input_with_zips(&["90210", "10001"], "0.0"));
+
+    assert_eq!(result.operations, vec![renamed("standard-90210", "Standard - Ships Free")]);
+}
+
+#[test]
+fn test_apply_runs_every_matching_rule() {
+    let config = Configuration::from_str(
+        r#"{"ruleSelection": "all", "rules": [{"zipPrefix": "9", "message": "West"}, {"zips": "90210", "mode": "hide"}]}"#,
+    )
+    .unwrap();
+
+    let result = apply(&config, &input_with_zips(&["90210", "94105"], "0.0"));
+
+    assert_eq!(
+        result.operations,
+        vec![
+            renamed("standard-90210", "Standard - West"),
+            renamed("standard-94105", "Standard - West"),
+            hidden("standard-90210"),
+        ]
+    );
+}
+
+#[test]
+fn test_operations_are_in_the_same_order_whatever_the_group_order() {
+    let config = Configuration::from_str(
+        r#"{"ruleSelection": "all", "rules": [{"zips": ["10001", "90210"], "mode": "hide"}, {"zipPrefix": "9", "message": "West"}]}"#,
+    )
+    .unwrap();
+
+    let result = apply(&config, &input_with_zips(&["90210", "10001", "94105"], "0.0"));
+
+    assert_eq!(
+        result.operations,
+        vec![
+            renamed("standard-90210", "Standard - West"),
+            renamed("standard-94105", "Standard - West"),
+            hidden("standard-10001"),
+            hidden("standard-90210"),
+        ]
+    );
+    assert_eq!(apply(&config, &input_with_zips(&["94105", "90210", "10001"], "0.0")), result);
+}
+
+#[test]
+fn test_zip_match_length_compares_the_first_characters() {
+    let config = Configuration::from_str(r#"{"zips": ["90210", "10001"], "zipMatchLength": 3, "message": "West"}"#).unwrap();
+
+    assert_eq!(
+        apply(&config, &input_with_zips(&["90299", "10101", "60601"], "0.0")).operations,
+        vec![renamed("standard-90299", "Standard - West")]
+    );
+}
+
+#[test]
+fn test_zip_match_length_compares_shorter_zips_whole() {
+    let config = Configuration::from_str(r#"{"zips": ["90210", "90"], "zipMatchLength": 3, "message": "West"}"#).unwrap();
+
+    // "90" only matches the configured "90", not the "902" section of "90210"
+    assert_eq!(
+        apply(&config, &input_with_zips(&["90", "9", "90512"], "0.0")).operations,
+        vec![renamed("standard-90", "Standard - West")]
+    );
+}
+
+#[test]
+fn test_zero_zip_match_length_is_invalid() {
+    let config = Configuration::from_str(r#"{"zip": "90210", "zipMatchLength": 0, "message": "West"}"#).unwrap();
+
+    assert_eq!(config.validate(), Err(vec!["rules[0]: zipMatchLength must be at least 1".to_string()]));
+}
+
+#[test]
+fn test_apply_respects_min_cart_total() {
+    let config = Configuration::from_str(r#"{"zips": ["90210"], "message": "Ships Free", "minCartTotal": "50.00"}"#).unwrap();
+
+    assert!(apply(&config, &input_with_zips(&["90210"], "49.99")).operations.is_empty());
+    assert_eq!(apply(&config, &input_with_zips(&["90210"], "50.00")).operations.len(), 1);
+}
+
+#[test]
+fn test_message_tiers_select_the_highest_tier_the_cart_total_exceeds() {
+    let config = Configuration::from_str(
+        r#"{"zips": ["90210"], "message": "Surcharge", "messageTiers": [[200, "Large surcharge"], ["50.00", "Small surcharge"]]}"#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        apply(&config, &input_with_zips(&["90210"], "120.00")).operations,
+        vec![renamed("standard-90210", "Standard - Small surcharge")]
+    );
+    assert_eq!(
+        apply(&config, &input_with_zips(&["90210"], "200.01")).operations,
+        vec![renamed("standard-90210", "Standard - Large surcharge")]
+    );
+}
+
+#[test]
+fn test_cart_total_at_a_tier_threshold_does_not_exceed_it() {
+    let config = Configuration::from_str(
+        r#"{"zips": ["90210"], "message": "Surcharge", "messageTiers": [["50.00", "Small surcharge"], [200, "Large surcharge"]]}"#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        apply(&config, &input_with_zips(&["90210"], "200.00")).operations,
+        vec![renamed("standard-90210", "Standard - Small surcharge")]
+    );
+    assert_eq!(
+        apply(&config, &input_with_zips(&["90210"], "50.00")).operations,
+        vec![renamed("standard-90210", "Standard - Surcharge")]
+    );
+}
+
+#[test]
+fn test_message_tiers_fall_back_to_the_message_below_every_tier() {
+    let config = Configuration::from_str(
+        r#"{"zips": ["90210"], "message": "Surcharge", "messageTiers": [["50.00", "Small surcharge"], [200, "Large surcharge"]]}"#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        apply(&config, &input_with_zips(&["90210"], "49.99")).operations,
+        vec![renamed("standard-90210", "Standard - Surcharge")]
+    );
+}
+
+#[test]
+fn test_max_operations_truncates_in_order_and_logs() -> Result<()> {
+    let config = r#"{\"zipPrefix\": \"9\", \"message\": \"West\", \"maxOperations\": 2}"#;
+
+    let result = run_with_zips(&["90210", "94105", "95014", "98101"], config)?;
+    let expected = output::FunctionResult {
+        operations: vec![
+            renamed("standard-90210", "Standard - West"),
+            renamed("standard-94105", "Standard - West"),
+        ],
+    };
+
+    assert_eq!(result, expected);
+    let logs = take_logs_without_rule_operations();
+    assert_eq!(logs.len(), 1);
+    assert_eq!(logs[0]["level"], "warn");
+    assert_eq!(logs[0]["dropped"], 2);
+    assert_eq!(logs[0]["max_operations"], 2);
+    Ok(())
+}
+
+#[test]
+fn test_max_operations_defaults_to_built_in_limit() -> Result<()> {
+    let zips: Vec<String> = (0..DEFAULT_MAX_OPERATIONS + 5).map(|index| format!("9{:04}", index)).collect();
+    let zips: Vec<&str> = zips.iter().map(String::as_str).collect();
+
+    let result = run_with_zips(&zips, r#"{\"zipPrefix\": \"9\", \"message\": \"West\"}"#)?;
+
+    assert_eq!(result.operations.len(), DEFAULT_MAX_OPERATIONS);
+    Ok(())
+}
+
+#[test]
+fn test_zip_messages_are_used_ahead_of_message() -> Result<()> {
+    let config = concat!(
+        r#"{\"zips\": [\"96799\", \"99950\", \"90210\"], \"message\": \"Standard delay\", "#,
+        r#"\"zipMessages\": {\"96799\": \"Island - 10 day delay\", \"99950\": \"Remote - 5 day delay\"}}"#
+    );
+
+    let result = run_with_zips(&["96799", "99950", "90210"], config)?;
+    let expected = output::FunctionResult {
+        operations: vec![
+            renamed("standard-90210", "Standard - Standard delay"),
+            renamed("standard-96799", "Standard - Island - 10 day delay"),
+            renamed("standard-99950", "Standard - Remote - 5 day delay"),
+        ],
+    };
+
+    assert_eq!(result, expected);
+    Ok(())
+}
+
+#[test]
+fn test_first_tiered_message_containing_the_zip_wins() -> Result<()> {
+    let config = concat!(
+        r#"{\"zips\": [\"10001\", \"10002\", \"90210\"], \"message\": \"Standard delay\", \"tieredMessages\": ["#,
+        r#"[[\"10001\"], \"Gridlocked - expect long delays\"], "#,
+        r#"[[\"10001\", \"10002\"], \"Congested - expect delays\"]]}"#
+    );
+
+    let result = run_with_zips(&["10001", "10002", "90210"], config)?;
+    let expected = output::FunctionResult {
+        operations: vec![
+            renamed("standard-10001", "Standard - Gridlocked - expect long delays"),
+            renamed("standard-10002", "Standard - Congested - expect delays"),
+            renamed("standard-90210", "Standard - Standard delay"),
+        ],
+    };
+
+    assert_eq!(result, expected);
+    Ok(())
+}
+
+#[test]
+fn test_invalid_message_tiers_are_rejected() {
+    let config = Configuration::from_str(r#"{"zip": "90210", "messageTiers": [[-1, "Refund"], [50, ""]]}"#).unwrap();
+
+    assert_eq!(
+        config.validate(),
+        Err(vec![
+            "rules[0]: messageTiers must not have negative thresholds".to_string(),
+            "rules[0]: messageTiers must not have empty messages".to_string(),
+        ])
+    );
+}
+
+#[test]
+fn test_empty_rule_id_is_invalid() {
+    let config = Configuration::from_str(r#"{"zip": "90210", "message": "Delay", "id": ""}"#).unwrap();
+
+    assert_eq!(config.validate(), Err(vec!["rules[0]: id must not be empty".to_string()]));
+}
+
+#[test]
+fn test_empty_option_message_is_invalid() {
+    let config = Configuration::from_str(r#"{"zip": "90210", "message": "Delay", "optionMessages": {"standard": ""}}"#).unwrap();
+
+    assert_eq!(config.validate(), Err(vec!["rules[0]: optionMessages must not have empty messages".to_string()]));
+}
+
+#[test]
+fn test_empty_tiered_message_is_invalid() {
+    let config = Configuration::from_str(r#"{"zip": "90210", "message": "Delay", "tieredMessages": [[["90210"], ""]]}"#).unwrap();
+
+    assert_eq!(config.validate(), Err(vec!["rules[0]: tieredMessages must not have empty messages".to_string()]));
+}
+
+#[test]
+fn test_missing_metafield_returns_no_changes_and_logs_the_field() -> Result<()> {
+    let result = run_function_with_input(
+        function,
+        r#"
+            {
+                "cart": {
+                    "cost": { "totalAmount": { "amount": "0.0" } },
+                    "lines": [],
+                    "deliveryGroups": [
+                        {
+                            "deliveryAddress": { "zip": "90210" },
+                            "deliveryOptions": [{ "handle": "standard", "title": "Standard", "cost": { "amount": "5.0" } }]
+                        }
+                    ]
+                },
+                "localization": { "language": { "isoCode": "EN" } },
+                "deliveryCustomization": {
+                    "metafield": null
+                }
+            }
+        "#,
+    )?;
+
+    assert_eq!(result, output::FunctionResult { operations: vec![] });
+    let logs = take_logs();
+    assert_eq!(logs.len(), 1);
+    assert_eq!(logs[0]["level"], "info");
+    assert_eq!(logs[0]["field"], "deliveryCustomization.metafield");
+    Ok(())
+}
+
+#[test]
+fn test_safe_config_returns_none_for_unusable_metafields() {
+    let metafield = |value: &str| Some(input::InputDeliveryCustomizationMetafield { value: value.to_string() });
+
+    for metafield in [None, metafield(""), metafield("[]")] {
+        let mut input = input_with_zips(&["90210"], "0.0");
+        input.delivery_customization.metafield = metafield;
+
+        assert!(safe_config(&input).is_none());
+    }
+}
+
+fn run_with_language(language: &str, config: &str) -> Result<output::FunctionResult> {
+    let mut input = base_input();
+    input["localization"]["language"]["isoCode"] = json!(language);
+    run_with_input(input, config)
+}
+
+const LOCALIZED_MESSAGES: &str =
+    r#"{\"zip\": \"90210\", \"message\": \"2 day delay\", \"messagesByLocale\": {\"FR\": \"2 jours de retard\", \"DE\": \"2 Tage Verspätung\"}}"#;
+
+#[test]
+fn test_messages_by_locale_use_buyer_language() -> Result<()> {
+    assert_eq!(run_with_language("FR", LOCALIZED_MESSAGES)?, renamed_standard("Standard - 2 jours de retard"));
+    assert_eq!(run_with_language("DE", LOCALIZED_MESSAGES)?, renamed_standard("Standard - 2 Tage Verspätung"));
+    Ok(())
+}
+
+#[test]
+fn test_messages_by_locale_fall_back_to_message() -> Result<()> {
+    // Shopify always sends a language, so an unconfigured one is the only way to miss a translation
+    assert_eq!(run_with_language("ES", LOCALIZED_MESSAGES)?, renamed_standard("Standard - 2 day delay"));
+    assert_eq!(run_with_language("EN", LOCALIZED_MESSAGES)?, renamed_standard("Standard - 2 day delay"));
+    Ok(())
+}
+
+#[test]
+fn test_zip_messages_take_precedence_over_messages_by_locale() -> Result<()> {
+    let config = r#"{\"zip\": \"90210\", \"message\": \"Delay\", \"zipMessages\": {\"90210\": \"Island\"}, \"messagesByLocale\": {\"FR\": \"Retard\"}}"#;
+
+    assert_eq!(run_with_language("FR", config)?, renamed_standard("Standard - Island"));
+    Ok(())
+}
+
+fn moved(handle: &str, index: i64) -> output::Operation {
+    output::Operation::move_to(output::MoveOperation {
+        delivery_option_handle: handle.to_string(),
+        index,
+    })
+}
+
+#[test]
+fn test_move_targets_resolve_against_group_options() -> Result<()> {
+    let options = r#"[
+        { "handle": "freight", "title": "Freight", "cost": { "amount": "5.0" } },
+        { "handle": "standard", "title": "Standard", "cost": { "amount": "5.0" } },
+        { "handle": "express", "title": "Express", "cost": { "amount": "5.0" } }
+    ]"#;
+    let config = |target: &str| {
+        format!(r#"{{\"zip\": \"90210\", \"titleContains\": \"Freight\", \"mode\": {{\"move\": {}}}}}"#, target)
+    };
+
+    let top = run_with_options("90210", options, &config(r#"\"top\""#))?;
+    let bottom = run_with_options("90210", options, &config(r#"\"bottom\""#))?;
+    let index = run_with_options("90210", options, &config(r#"{\"index\": 1}"#))?;
+
+    assert_eq!(top.operations, vec![moved("freight", 0)]);
+    assert_eq!(bottom.operations, vec![moved("freight", 2)]);
+    assert_eq!(index.operations, vec![moved("freight", 1)]);
+    Ok(())
+}
+
+#[test]
+fn test_validate_collects_every_error() {
+    let config = Configuration::from_str(
+        r#"{"maxOperations": 0, "rules": [{"zips": ["90210", "9021!"], "message": "Ok"}, {"zipPrefix": "", "titleContains": ""}]}"#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        config.validate(),
+        Err(vec![
+            "maxOperations must be at least 1".to_string(),
+            r#"rules[0]: zip "9021!" may only contain letters, digits, spaces and dashes"#.to_string(),
+            "rules[1]: zipPrefix must be a non-empty zip prefix".to_string(),
+            "rules[1]: titleContains must not be empty".to_string(),
+            "rules[1]: message is required when renaming".to_string(),
+        ])
+    );
+}
+
+#[test]
+fn test_invalid_configuration_logs_errors_and_returns_no_changes() -> Result<()> {
+    let config = r#"{\"zips\": [\"90210\"], \"minCartTotal\": -5}"#;
+
+    assert_eq!(run_with_zip("90210", config)?, output::FunctionResult { operations: vec![] });
+    let logs = take_logs();
+    assert_eq!(logs.len(), 1);
+    assert_eq!(logs[0]["level"], "error");
+    assert_eq!(
+        logs[0]["errors"],
+        serde_json::json!(["minCartTotal must not be negative", "rules[0]: message is required when renaming"])
+    );
+    Ok(())
+}
+
+#[test]
+fn test_doubly_encoded_configuration_parses_the_same() {
+    let value = r#"{"zips": ["90210"], "message": "Ships Free", "titleRegex": "^Standard"}"#;
+    let encoded = serde_json::to_string(value).unwrap();
+
+    assert!(Configuration::from_str(&encoded).unwrap() == Configuration::from_str(value).unwrap());
+}
+
+#[test]
+fn test_free_shipping_label_composes_with_zip_rename() -> Result<()> {
+    let result = run_function_with_input(
+        function,
+        r#"
+            {
+                "cart": {
+                    "cost": { "totalAmount": { "amount": "0.0" } },
+                    "lines": [],
+                    "deliveryGroups": [
+                        {
+                            "deliveryAddress": { "zip": "99501" },
+                            "deliveryOptions": [
+                                { "handle": "standard", "title": "Standard", "cost": { "amount": "0.00" } },
+                                { "handle": "express", "title": "Express", "cost": { "amount": "12.50" } }
+                            ]
+                        },
+                        {
+                            "deliveryAddress": { "zip": "10001" },
+                            "deliveryOptions": [
+                                { "handle": "economy", "title": "Economy", "cost": { "amount": "0" } },
+                                { "handle": "overnight", "title": "Overnight", "cost": { "amount": "30.00" } }
+                            ]
+                        }
+                    ]
+                },
+                "localization": { "language": { "isoCode": "EN" } },
+                "deliveryCustomization": {
+                    "metafield": {
+                        "value": "{\"zip\": \"99501\", \"message\": \"Remote area\", \"freeShippingLabel\": \"FREE\"}"
+                    }
+                }
+            }
+        "#,
+    )?;
+    let expected = output::FunctionResult {
+        operations: vec![
+            renamed("economy", "Economy - FREE"),
+            renamed("express", "Express - Remote area"),
+            renamed("standard", "Standard - Remote area - FREE"),
+        ],
+    };
+
+    assert_eq!(result, expected);
+    Ok(())
+}
+
+#[test]
+fn test_free_shipping_label_alone_labels_free_options() -> Result<()> {
+    let options = r#"[
+        { "handle": "economy", "title": "Economy", "cost": { "amount": "0.0" } },
+        { "handle": "standard", "title": "Standard", "cost": { "amount": "5.0" } }
+    ]"#;
+    let config = r#"{\"freeShippingLabel\": \"FREE\"}"#;
+
+    let result = run_with_options("10001", options, config)?;
+
+    assert_eq!(result.operations, vec![renamed("economy", "Economy - FREE")]);
+    assert!(take_logs().iter().all(|log| log["level"] != "error"));
+    Ok(())
+}
+
+#[test]
+fn test_free_shipping_label_is_not_added_twice() {
+    let config = Configuration::from_str(r#"{"zips": [], "freeShippingLabel": "FREE"}"#).unwrap();
+    let mut input = input_with_zips(&["10001"], "0.0");
+    input.cart.delivery_groups[0].delivery_options[0].cost.amount = "0.0".to_string();
+    input.cart.delivery_groups[0].delivery_options[0].title = Some("Standard - FREE".to_string());
+
+    assert_eq!(apply(&config, &input).operations, vec![]);
+}
+
+#[test]
+fn test_empty_free_shipping_label_is_invalid() {
+    let config = Configuration::from_str(r#"{"zip": "90210", "message": "Hi", "freeShippingLabel": ""}"#).unwrap();
+
+    assert_eq!(config.validate(), Err(vec!["freeShippingLabel must not be empty".to_string()]));
+}
+
+#[test]
+fn test_input_query_targets_the_configured_metafield() {
+    let namespace = option_env!("DELIVERY_CONFIG_METAFIELD_NAMESPACE").unwrap_or("$app:delivery-customization");
+    let key = option_env!("DELIVERY_CONFIG_METAFIELD_KEY").unwrap_or("function-configuration");
+
+    assert!(INPUT_QUERY.contains(&format!("namespace: \"{}\"", namespace)));
+    assert!(INPUT_QUERY.contains(&format!("key: \"{}\"", key)));
+}
+
+#[test]
+fn test_building_leaves_the_tracked_input_query_as_it_is() {
+    let query = include_str!("../input.graphql");
+
+    assert!(query.contains("namespace: \"$app:delivery-customization\""));
+    assert!(query.contains("key: \"function-configuration\""));
+}
+
+// Run the function with the given `attribute` JSON, or without the attribute for `None`
+fn run_with_attribute(attribute: Option<&str>, config: &str) -> Result<output::FunctionResult> {
+    let mut input = base_input();
+    if let Some(attribute) = attribute {
+        input["cart"]["attribute"] = serde_json::from_str(attribute)?;
+    }
+    run_with_input(input, config)
+}
+
+const ATTRIBUTE_OVERRIDE: &str =
+    r#"{\"zip\": \"90210\", \"message\": \"2 day delay\", \"attributeOverrideKey\": \"delivery_note_override\"}"#;
+
+#[test]
+fn test_cart_attribute_overrides_message() -> Result<()> {
+    let attribute = r#"{ "key": "delivery_note_override", "value": "Leave at the back door" }"#;
+
+    assert_eq!(
+        run_with_attribute(Some(attribute), ATTRIBUTE_OVERRIDE)?,
+        renamed_standard("Standard - Leave at the back door")
+    );
+    Ok(())
+}
+
+#[test]
+fn test_empty_or_absent_cart_attribute_keeps_message() -> Result<()> {
+    let empty = r#"{ "key": "delivery_note_override", "value": "" }"#;
+    let unset = r#"{ "key": "delivery_note_override", "value": null }"#;
+
+    assert_eq!(run_with_attribute(Some(empty), ATTRIBUTE_OVERRIDE)?, renamed_standard("Standard - 2 day delay"));
+    assert_eq!(run_with_attribute(Some(unset), ATTRIBUTE_OVERRIDE)?, renamed_standard("Standard - 2 day delay"));
+    assert_eq!(run_with_attribute(Some("null"), ATTRIBUTE_OVERRIDE)?, renamed_standard("Standard - 2 day delay"));
+    assert_eq!(run_with_attribute(None, ATTRIBUTE_OVERRIDE)?, renamed_standard("Standard - 2 day delay"));
+    Ok(())
+}
+
+#[test]
+fn test_cart_attribute_is_ignored_without_override_key() -> Result<()> {
+    let attribute = r#"{ "key": "delivery_note_override", "value": "Leave at the back door" }"#;
+    let config = r#"{\"zip\": \"90210\", \"message\": \"2 day delay\"}"#;
+
+    assert_eq!(run_with_attribute(Some(attribute), config)?, renamed_standard("Standard - 2 day delay"));
+    Ok(())
+}
+
+#[test]
+fn test_attribute_override_key_must_be_a_selected_attribute() {
+    let config = Configuration::from_str(
+        r#"{"zip": "90210", "message": "2 day delay", "attributeOverrideKey": "gift_note"}"#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        config.validate(),
+        Err(vec![
+            r#"attributeOverrideKey "gift_note" isn't selected, the supported attributes are delivery_note_override, delivery_note_applied"#
+                .to_string(),
+        ])
+    );
+}
+
+#[test]
+fn test_every_supported_cart_attribute_is_selected() {
+    let query = include_str!("../input.graphql");
+
+    for (key, _) in CART_ATTRIBUTES {
+        assert!(query.contains(&format!("attribute(key: \"{}\")", key)), "{} isn't selected", key);
+    }
+}
+
+// The input for a cart in 90210 with the `delivery_note_applied` marker set to `value`, or without it
+fn input_with_marker(value: Option<&str>) -> input::ResponseData {
+    let mut input = input_with_zips(&["90210"], "0.0");
+    input.cart.delivery_note_applied = value.map(|value| {
+        serde_json::from_value(serde_json::json!({ "key": "delivery_note_applied", "value": value })).unwrap()
+    });
+    input
+}
+
+#[test]
+fn test_rule_is_skipped_when_its_marker_attribute_is_set() {
+    let config = Configuration::from_str(
+        r#"{"ruleSelection": "all", "rules": [{"zip": "90210", "message": "2 day delay", "skipIfAttribute": "delivery_note_applied"}, {"zip": "90210", "mode": "hide"}]}"#,
+    )
+    .unwrap();
+
+    assert_eq!(apply(&config, &input_with_marker(Some("true"))).operations, vec![hidden("standard-90210")]);
+    assert_eq!(apply(&config, &input_with_marker(Some(" 1 "))).operations, vec![hidden("standard-90210")]);
+}
+
+#[test]
+fn test_rule_applies_when_its_marker_attribute_is_absent_or_off() {
+    let config = Configuration::from_str(
+        r#"{"zip": "90210", "message": "2 day delay", "skipIfAttribute": "delivery_note_applied"}"#,
+    )
+    .unwrap();
+    let expected = vec![renamed("standard-90210", "Standard - 2 day delay")];
+
+    assert_eq!(apply(&config, &input_with_marker(None)).operations, expected);
+    assert_eq!(apply(&config, &input_with_marker(Some(""))).operations, expected);
+    assert_eq!(apply(&config, &input_with_marker(Some("False"))).operations, expected);
+    assert_eq!(apply(&config, &input_with_marker(Some("0"))).operations, expected);
+}
+
+#[test]
+fn test_marker_attribute_only_skips_rules_naming_it() {
+    let config = Configuration::from_str(
+        r#"{"zip": "90210", "message": "2 day delay", "skipIfAttribute": "delivery_note_override"}"#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        apply(&config, &input_with_marker(Some("true"))).operations,
+        vec![renamed("standard-90210", "Standard - 2 day delay")]
+    );
+}
+
+#[test]
+fn test_skip_if_attribute_must_be_a_selected_attribute() {
+    let config = Configuration::from_str(
+        r#"{"zip": "90210", "message": "Delay", "skipIfAttribute": "note_shown"}"#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        config.validate(),
+        Err(vec![
+            r#"rules[0]: skipIfAttribute "note_shown" isn't selected, the supported attributes are delivery_note_override, delivery_note_applied"#
+                .to_string(),
+        ])
+    );
+}
+
+#[test]
+fn test_empty_skip_if_attribute_is_invalid() {
+    let config = Configuration::from_str(r#"{"zip": "90210", "message": "Delay", "skipIfAttribute": ""}"#).unwrap();
+
+    assert_eq!(config.validate(), Err(vec!["rules[0]: skipIfAttribute must not be empty".to_string()]));
+}
+
+#[test]
+fn test_large_zip_sets_match_exactly() {
+    let zips: Vec<String> = (0..10_000).map(|zip| format!("{:05}", zip * 3)).collect();
+    let config = Configuration::from_str(&serde_json::json!({
+        "zips": zips,
+        "excludeZips": ["00300"],
+        "message": "Remote"
+    }).to_string()).unwrap();
+
+    let result = apply(&config, &input_with_zips(&["29997", "29998", "00300", "00003", " 00003", "03"], "0.0"));
+
+    assert_eq!(result.operations, vec![
+        renamed("standard- 00003", "Standard - Remote"),
+        renamed("standard-00003", "Standard - Remote"),
+        renamed("standard-29997", "Standard - Remote"),
+    ]);
+}
+
+#[test]
+fn test_zips_match_after_whitespace_normalization() {
+    let config = Configuration::from_str(
+        r#"{"zips": [" 99501 ", "E1  6AN\n"], "zipMessages": {" E1 6AN": "London"}, "message": "Remote"}"#,
+    ).unwrap();
+
+    let result = apply(&config, &input_with_zips(&["99501", " E1 6AN ", "10001"], "0.0"));
+
+    assert_eq!(result.operations, vec![
+        renamed("standard- E1 6AN ", "Standard - London"),
+        renamed("standard-99501", "Standard - Remote"),
+    ]);
+}
+
+const TWO_PRODUCT_LINES: &str = r#"[
+    {
+        "merchandise": {
+            "__typename": "ProductVariant",
+            "id": "gid://shopify/ProductVariant/11",
+            "product": { "id": "gid://shopify/Product/1" }
+        }
+    },
+    {
+        "merchandise": {
+            "__typename": "ProductVariant",
+            "id": "gid://shopify/ProductVariant/21",
+            "product": { "id": "gid://shopify/Product/2" }
+        }
+    }
+]"#;
+
+// Cart lines for the given variant and product ids, in order
+fn variant_lines(variants: &[(&str, &str)]) -> String {
+    let lines: Vec<_> = variants
+        .iter()
+        .map(|(variant, product)| json!({
+            "merchandise": {
+                "__typename": "ProductVariant",
+                "id": format!("gid://shopify/ProductVariant/{}", variant),
+                "product": { "id": format!("gid://shopify/Product/{}", product) }
+            }
+        }))
+        .collect();
+    json!(lines).to_string()
+}
+
+#[test]
+fn test_min_distinct_items_renames_carts_with_many_items() -> Result<()> {
+    let config = r#"{\"zip\": \"90210\", \"message\": \"Split shipment\", \"minDistinctItems\": 3}"#;
+    // Variants of the same product are distinct items
+    let lines = variant_lines(&[("11", "1"), ("12", "1"), ("21", "2")]);
+
+    assert_eq!(run_with_lines(&lines, config)?, renamed_standard("Standard - Split shipment"));
+    Ok(())
+}
+
+#[test]
+fn test_min_distinct_items_leaves_carts_with_few_items_alone() -> Result<()> {
+    let config = r#"{\"zip\": \"90210\", \"message\": \"Split shipment\", \"minDistinctItems\": 3}"#;
+
+    assert_eq!(run_with_lines(TWO_PRODUCT_LINES, config)?, output::FunctionResult { operations: vec![] });
+    let logs = take_logs();
+    assert_eq!(logs[0]["message"], "Cart doesn't have enough distinct items, no need to customize delivery options.");
+    assert_eq!(logs[0]["distinct_items"], 2);
+    Ok(())
+}
+
+#[test]
+fn test_min_distinct_items_counts_duplicate_lines_of_a_variant_once() -> Result<()> {
+    let config = r#"{\"zip\": \"90210\", \"message\": \"Split shipment\", \"minDistinctItems\": 3}"#;
+    let lines = variant_lines(&[("11", "1"), ("11", "1"), ("11", "1"), ("21", "2")]);
+
+    assert_eq!(run_with_lines(&lines, config)?, output::FunctionResult { operations: vec![] });
+    Ok(())
+}
+
+#[test]
+fn test_product_match_mode_any_needs_one_product() -> Result<()> {
+    let config = r#"{\"zip\": \"90210\", \"message\": \"Fragile\", \"productIds\": [\"gid://shopify/Product/1\", \"gid://shopify/Product/3\"], \"productMatchMode\": \"any\"}"#;
+
+    assert_eq!(run_with_lines(FRAGILE_LINES, config)?, renamed_standard("Standard - Fragile"));
+    assert_eq!(run_with_lines(TWO_PRODUCT_LINES, config)?, renamed_standard("Standard - Fragile"));
+    Ok(())
+}
+
+#[test]
+fn test_product_match_mode_all_needs_every_product() -> Result<()> {
+    let config = r#"{\"zip\": \"90210\", \"message\": \"Fragile\", \"productIds\": [\"gid://shopify/Product/1\", \"gid://shopify/Product/2\"], \"productMatchMode\": \"all\"}"#;
+
+    assert_eq!(run_with_lines(FRAGILE_LINES, config)?, output::FunctionResult { operations: vec![] });
+    assert_eq!(run_with_lines(TWO_PRODUCT_LINES, config)?, renamed_standard("Standard - Fragile"));
+    Ok(())
+}
+
+#[test]
+fn test_rename_rule_can_also_move_options() -> Result<()> {
+    let config = r#"{\"zip\": \"90210\", \"titleContains\": \"Express\", \"message\": \"Fastest\", \"moveTo\": \"top\"}"#;
+
+    let result = run_with_options("90210", STANDARD_AND_EXPRESS, config)?;
+
+    assert_eq!(result.operations, vec![
+        renamed("express", "Priority Express - Fastest"),
+        moved("express", 0),
+    ]);
+    Ok(())
+}
+
+#[test]
+fn test_move_to_requires_rename_mode() {
+    let config = Configuration::from_str(r#"{"zip": "90210", "mode": "hide", "moveTo": "top"}"#).unwrap();
+
+    assert_eq!(config.validate(), Err(vec!["rules[0]: moveTo only applies when renaming".to_string()]));
+}
+
+// Builds a configuration rule by rule for tests, filling in what `Configuration::from_str` would
+struct ConfigurationBuilder {
+    config: Configuration,
+    rule: Rule
+}
+
+impl ConfigurationBuilder {
+    fn new() -> Self {
+        ConfigurationBuilder { config: Configuration::default(), rule: ConfigurationBuilder::default_rule() }
+    }
+
+    // A rule with the defaults serde fills in
+    fn default_rule() -> Rule {
+        Rule { product_match_mode: LogicMode::Any, ..Rule::default() }
+    }
+
+    fn zip(mut self, zip: &str) -> Self {
+        self.rule.zips.insert(zip.to_string());
+        self
+    }
+
+    fn message(mut self, message: &str) -> Self {
+        self.rule.message = message.to_string();
+        self
+    }
+
+    fn mode(mut self, mode: DeliveryAction) -> Self {
+        self.rule.mode = mode;
+        self
+    }
+
+    fn title_contains(mut self, text: &str) -> Self {
+        self.rule.title_contains = Some(text.to_string());
+        self
+    }
+
+    fn rule_selection(mut self, rule_selection: RuleSelection) -> Self {
+        self.config.rule_selection = rule_selection;
+        self
+    }
+
+    // Finish the current rule and start another one
+    fn next_rule(mut self) -> Self {
+        let rule = std::mem::replace(&mut self.rule, ConfigurationBuilder::default_rule());
+        self.config.rules.push(rule);
+        self
+    }
+
+    fn build(self) -> Configuration {
+        let mut config = self.next_rule().config;
+        config.prepare();
+        config
+    }
+}
+
+#[test]
+fn test_configuration_builder_matches_parsed_configuration() {
+    let built = ConfigurationBuilder::new()
+        .zip("99501")
+        .message("Remote area")
+        .next_rule()
+        .zip("99501")
+        .title_contains("Express")
+        .mode(DeliveryAction::Hide)
+        .rule_selection(RuleSelection::All)
+        .build();
+    let parsed = Configuration::from_str(
+        r#"{"rules": [{"zips": ["99501"], "message": "Remote area"}, {"zips": ["99501"], "titleContains": "Express", "mode": "hide"}], "ruleSelection": "all"}"#,
+    ).unwrap();
+
+    assert!(built == parsed);
+    assert_eq!(built.validate(), Ok(()));
+    assert_eq!(apply(&built, &input_with_zips(&["99501"], "0.0")).operations, vec![
+        renamed("standard-99501", "Standard - Remote area"),
+    ]);
+}
+
+#[test]
+fn test_match_all_renames_every_option_in_every_group() -> Result<()> {
+    let result = run_function_with_input(
+        function,
+        r#"
+            {
+                "cart": {
+                    "cost": { "totalAmount": { "amount": "0.0" } },
+                    "lines": [],
+                    "deliveryGroups": [
+                        {
+                            "deliveryAddress": { "zip": "10001" },
+                            "deliveryOptions": [
+                                { "handle": "standard", "title": "Standard", "cost": { "amount": "5.0" } },
+                                { "handle": "express", "title": "Express", "cost": { "amount": "15.0" } }
+                            ]
+                        },
+                        {
+                            "deliveryAddress": null,
+                            "deliveryOptions": [{ "handle": "pickup", "title": "Pickup", "cost": { "amount": "0.0" } }]
+                        }
+                    ]
+                },
+                "localization": { "language": { "isoCode": "EN" } },
+                "deliveryCustomization": {
+                    "metafield": {
+                        "value": "{\"matchAll\": true, \"zip\": \"99501\", \"titleContains\": \"Freight\", \"message\": \"Holiday delays\"}"
+                    }
+                }
+            }
+        "#,
+    )?;
+    let expected = output::FunctionResult {
+        operations: vec![
+            renamed("express", "Express - Holiday delays"),
+            renamed("pickup", "Pickup - Holiday delays"),
+            renamed("standard", "Standard - Holiday delays"),
+        ],
+    };
+
+    assert_eq!(result, expected);
+    Ok(())
+}
+
+#[test]
+fn test_operation_constructors_set_exactly_one_action() {
+    let handle = || "standard".to_string();
+    let rename = output::Operation::rename(output::RenameOperation { delivery_option_handle: handle(), title: "Standard".to_string() });
+    let hide = output::Operation::hide(output::HideOperation { delivery_option_handle: handle() });
+    let move_ = output::Operation::move_to(output::MoveOperation { delivery_option_handle: handle(), index: 1 });
+
+    assert!(rename.rename.is_some() && rename.hide.is_none() && rename.move_.is_none());
+    assert!(hide.rename.is_none() && hide.hide.is_some() && hide.move_.is_none());
+    assert!(move_.rename.is_none() && move_.hide.is_none() && move_.move_.is_some());
+}
+
+#[test]
+fn test_min_option_cost_only_renames_expensive_options() -> Result<()> {
+    let options = r#"[
+        { "handle": "standard", "title": "Standard", "cost": { "amount": "4.99" } },
+        { "handle": "express", "title": "Express", "cost": { "amount": "15.00" } },
+        { "handle": "courier", "title": "Courier", "cost": { "amount": "not-a-number" } }
+    ]"#;
+    let config = r#"{\"zip\": \"90210\", \"message\": \"Signature required\", \"minOptionCost\": \"15.00\"}"#;
+
+    let result = run_with_options("90210", options, config)?;
+
+    assert_eq!(result.operations, vec![renamed("express", "Express - Signature required")]);
+    Ok(())
+}
+
+#[test]
+fn test_negative_min_option_cost_is_invalid() {
+    let config = Configuration::from_str(r#"{"zip": "90210", "message": "Hi", "minOptionCost": -1}"#).unwrap();
+
+    assert_eq!(config.validate(), Err(vec!["rules[0]: minOptionCost must not be negative".to_string()]));
+}
+
+#[test]
+fn test_config_override_supersedes_the_metafield() -> Result<()> {
+    let config = r#"{\"zip\": \"90210\", \"message\": \"Metafield\"}"#;
+    std::env::set_var(CONFIG_OVERRIDE_VARIABLE, r#"{"zip": "90210", "message": "Override"}"#);
+
+    let ignored = run_with_zip("90210", config)?;
+    READ_CONFIG_OVERRIDE.with(|read| read.set(true));
+    let overridden = run_with_zip("90210", config)?;
+    READ_CONFIG_OVERRIDE.with(|read| read.set(false));
+    std::env::remove_var(CONFIG_OVERRIDE_VARIABLE);
+
+    assert_eq!(ignored, renamed_standard("Standard - Metafield"));
+    assert_eq!(overridden, renamed_standard("Standard - Override"));
+    assert_eq!(take_logs_without_rule_operations()[0]["message"], "Using the configuration from the environment instead of the metafield.");
+    Ok(())
+}
+
+#[test]
+fn test_disabled_configuration_returns_no_changes_and_logs() -> Result<()> {
+    let result = run_with_zip("90210", r#"{\"enabled\": false, \"zip\": \"90210\", \"message\": \"Delayed\"}"#)?;
+
+    assert_eq!(result, output::FunctionResult { operations: vec![] });
+    assert_eq!(
+        take_logs(),
+        vec![json!({ "level": "info", "message": "Customization is disabled, no need to customize delivery options." })]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_enabled_configuration_customizes_as_usual() -> Result<()> {
+    let result = run_with_zip("90210", r#"{\"enabled\": true, \"zip\": \"90210\", \"message\": \"Delayed\"}"#)?;
+
+    assert_eq!(result, renamed_standard("Standard - Delayed"));
+    Ok(())
+}
+
+#[test]
+fn test_unsupported_operations_are_dropped_and_logged() -> Result<()> {
+    let config = r#"{\"zip\": \"90210\", \"message\": \"Delayed\", \"moveTo\": \"top\", \"supportedOperations\": [\"rename\", \"hide\"]}"#;
+
+    assert_eq!(run_with_zip("90210", config)?, renamed_standard("Standard - Delayed"));
+    assert_eq!(
+        take_logs_without_rule_operations(),
+        vec![json!({
+            "level": "warn",
+            "message": "Operation is not supported, dropping it.",
+            "kind": "move",
+            "delivery_option_handle": "standard"
+        })]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_empty_supported_operations_are_invalid() {
+    let config = Configuration::from_str(r#"{"zip": "90210", "message": "Hi", "supportedOperations": []}"#).unwrap();
+
+    assert_eq!(config.validate(), Err(vec!["supportedOperations must not be empty".to_string()]));
+}
+
+#[test]
+fn test_profiling_logs_phase_durations_only_when_enabled() -> Result<()> {
+    run_with_zip("90210", r#"{\"zip\": \"90210\", \"message\": \"Delayed\"}"#)?;
+    assert!(take_logs_without_rule_operations().is_empty());
+
+    let result = run_with_zip("90210", r#"{\"zip\": \"90210\", \"message\": \"Delayed\", \"profile\": true}"#)?;
+
+    assert_eq!(result, renamed_standard("Standard - Delayed"));
+    let logs = take_logs_without_rule_operations();
+    assert_eq!(logs.len(), 1);
+    assert_eq!(logs[0]["message"], "Profiled the run.");
+    for field in ["parse_us", "filter_us", "build_us"] {
+        assert!(logs[0][field].is_u64(), "{} should be a duration", field);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_option_count_and_group_index_are_interpolated_per_group() {
+    let config = Configuration::from_str(
+        r#"{"zips": ["10001", "90210"], "message": "{option_count} options for {zip} in shipment {group_index}, {unknown}"}"#,
+    )
+    .unwrap();
+    let mut input = input_with_zips(&["60601", "10001", "90210"], "0.0");
+    let express = input::InputCartDeliveryGroupsDeliveryOptions {
+        handle: "express-10001".to_string(),
+        title: Some("Express".to_string()),
+        ..input.cart.delivery_groups[1].delivery_options[0].clone()
+    };
+    input.cart.delivery_groups[1].delivery_options.push(express);
+
+    assert_eq!(
+        apply(&config, &input).operations,
+        vec![
+            renamed("express-10001", "Express - 2 options for 10001 in shipment 1, {unknown}"),
+            renamed("standard-10001", "Standard - 2 options for 10001 in shipment 1, {unknown}"),
+            renamed("standard-90210", "Standard - 1 options for 90210 in shipment 2, {unknown}"),
+        ]
+    );
+}
+
+#[test]
+fn test_validate_zip_format_warns_about_malformed_zips_without_failing() {
+    let config = Configuration::from_str(concat!(
+        r#"{"validateZipFormat": true, "rules": ["#,
+        r#"{"zips": ["99501", "99501-1234", "9950"], "countryCodes": ["US"], "message": "Alaska"}, "#,
+        r#"{"zips": ["K1A 0B1", "k1a0b1", "K1A 0B"], "countryCodes": ["CA"], "message": "Ottawa"}, "#,
+        r#"{"zips": ["12-345"], "countryCodes": ["PL"], "message": "Unknown format"}]}"#
+    ))
+    .unwrap();
+
+    assert_eq!(config.validate(), Ok(()));
+    let warned: Vec<_> = take_logs().iter().map(|entry| (entry["rule"].clone(), entry["zip"].clone())).collect();
+    assert_eq!(warned, vec![(json!("rules[0]"), json!("9950")), (json!("rules[1]"), json!("K1A 0B"))]);
+}
+
+#[test]
+fn test_zip_formats_are_not_checked_by_default() {
+    let config = Configuration::from_str(r#"{"zips": ["9950"], "countryCodes": ["US"], "message": "Alaska"}"#).unwrap();
+
+    assert_eq!(config.validate(), Ok(()));
+    assert!(take_logs().is_empty());
+}
+
+#[test]
+fn test_strict_configuration_rejects_unknown_fields() -> Result<()> {
+    let result = run_with_zip("90210", r#"{\"strict\": true, \"zpi\": \"90210\", \"message\": \"Delayed\"}"#)?;
+
+    assert_eq!(result, output::FunctionResult { operations: vec![] });
+    let logs = take_logs();
+    assert_eq!(logs[0]["message"], "Unable to parse configuration value from metafield");
+    assert!(logs[0]["error"].as_str().unwrap().contains("unknown fields in strict configuration: zpi"));
+
+    let error = Configuration::from_str(r#"{"strict": true, "rules": [{"zips": ["90210"], "mesage": "Hi"}], "dryRuns": true}"#)
+        .err()
+        .unwrap();
+    assert!(error.to_string().contains("dryRuns, rules[0].mesage"));
+    Ok(())
+}
+
+#[test]
+fn test_lenient_configuration_ignores_unknown_fields() -> Result<()> {
+    let result = run_with_zip("90210", r#"{\"zpi\": \"00000\", \"zip\": \"90210\", \"message\": \"Delayed\"}"#)?;
+
+    assert_eq!(result, renamed_standard("Standard - Delayed"));
+    Ok(())
+}
+
+#[test]
+fn test_strict_configuration_accepts_known_fields() {
+    let config = Configuration::from_str(concat!(
+        r#"{"version": 2, "strict": true, "dryRun": false, "maxOperations": 10, "freeShippingLabel": "FREE", "#,
+        r#""rules": [{"zips": ["90210"], "zipPrefix": "100", "countryCodes": ["US"], "message": "Hi", "#,
+        r#""zipMessages": {"90210": "Hello"}, "mode": "rename", "moveTo": "top", "minOptionCost": "5.00"}]}"#
+    ));
+
+    assert!(config.is_ok());
+}
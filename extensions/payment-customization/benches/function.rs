@@ -0,0 +1,92 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use serde_json::json;
+
+// The function is a binary, so its source is compiled into the benchmark directly
+#[allow(dead_code)]
+#[path = "../src/main.rs"]
+mod payment;
+
+// Counts heap allocations, so the benchmark can report how many one run makes
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+  unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    System.alloc(layout)
+  }
+
+  unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    System.dealloc(ptr, layout)
+  }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const METHODS: usize = 50;
+const LINES: usize = 100;
+const RULES: usize = 100;
+
+// A cart with many lines and payment methods, and a configuration with many rules mixing names,
+// regexes and thresholds, all below the cart total so that every rule fires without logging
+fn large_input() -> serde_json::Value {
+  let rules: Vec<_> = (0..RULES)
+    .map(|rule| match rule % 3 {
+      0 => json!({ "paymentMethodNames": [format!("Method {}", rule % METHODS)], "cartTotal": rule % 5 * 100 }),
+      1 => json!({ "nameRegex": format!("^Method {}$", rule % METHODS), "cartTotal": 0, "renameTo": "Renamed" }),
+      _ => json!({ "paymentMethodNames": [format!("Method {}", rule % METHODS)], "minTotalQuantity": 50, "moveTo": "bottom" })
+    })
+    .collect();
+  let config = json!({ "version": 2, "rules": rules });
+  let lines: Vec<_> = (0..LINES)
+    .map(|_| json!({
+      "quantity": 2,
+      "merchandise": { "__typename": "ProductVariant", "weight": 250.0, "weightUnit": "GRAMS" }
+    }))
+    .collect();
+  let methods: Vec<_> = (0..METHODS)
+    .map(|method| json!({ "id": method.to_string(), "name": format!("Method {}", method) }))
+    .collect();
+  json!({
+    "cart": {
+      "deliveryGroups": [],
+      "lines": lines,
+      "cost": {
+        "subtotalAmount": { "amount": "480.00" },
+        "totalAmount": { "amount": "500.00", "currencyCode": "USD" }
+      }
+    },
+    "paymentMethods": methods,
+    "paymentCustomization": {
+      "metafield": { "value": config.to_string() }
+    }
+  })
+}
+
+fn bench_payment(c: &mut Criterion) {
+  let input = serde_json::from_value(large_input()).unwrap();
+  let config = payment::safe_config(&input).unwrap();
+
+  let before = ALLOCATIONS.load(Ordering::Relaxed);
+  let result = payment::apply(&config, &input);
+  println!(
+    "apply: {} operations, {} allocations",
+    result.operations.len(),
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+  );
+
+  c.bench_function("payment apply", |b| b.iter(|| payment::apply(&config, &input)));
+  c.bench_function("payment function", |b| {
+    b.iter_batched(|| input.clone(), payment::function, BatchSize::LargeInput)
+  });
+}
+
+// Only time and allocations are measured here. The release module's size is checked against a budget
+// by `cargo test -p shopify_config --test module_size -- --ignored`.
+criterion_group!(benches, bench_payment);
+criterion_main!(benches);
@@ -0,0 +1,18 @@
+use std::path::Path;
+use std::{env, fs};
+
+// Write the input query to the build's output directory, with its configuration metafield pointed at
+// `PAYMENT_CONFIG_METAFIELD_NAMESPACE` and/or `PAYMENT_CONFIG_METAFIELD_KEY` when they're set. The
+// tracked `input.graphql` is left as it is, so that unsetting them goes back to the default metafield.
+fn main() {
+  println!("cargo:rerun-if-changed=input.graphql");
+  println!("cargo:rerun-if-env-changed=PAYMENT_CONFIG_METAFIELD_NAMESPACE");
+  println!("cargo:rerun-if-env-changed=PAYMENT_CONFIG_METAFIELD_KEY");
+
+  let namespace = env::var("PAYMENT_CONFIG_METAFIELD_NAMESPACE").ok();
+  let key = env::var("PAYMENT_CONFIG_METAFIELD_KEY").ok();
+  let query = fs::read_to_string("input.graphql").expect("Unable to read input.graphql");
+  let targeted = shopify_config::query::target_metafield(&query, namespace.as_deref(), key.as_deref());
+  let out_dir = env::var("OUT_DIR").expect("Cargo should set OUT_DIR");
+  fs::write(Path::new(&out_dir).join("input.graphql"), targeted).expect("Unable to write the input query");
+}
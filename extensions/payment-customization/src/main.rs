@@ -1,62 +1,966 @@
 use shopify_function::prelude::*;
 use shopify_function::Result;
 
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
+
+use regex::{Regex, RegexBuilder};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
+use shopify_config::logging::{self, Level, Profiler};
+use shopify_config::{money, sort_operations, MoveTarget, OperationKind};
+
+// Version 1 configurations used `paymentMethodName` for the names, version 2 uses `paymentMethodNames`
+const CONFIGURATION_VERSION: u64 = 2;
 
-// Use the shopify_function crate to generate structs for the function input and output
+// Use the shopify_function crate to generate structs for the function input and output. Line weights
+// are floats, so the input types derive PartialEq without Eq.
 generate_types!(
   query_path = "./input.graphql",
-  schema_path = "./schema.graphql"
+  schema_path = "./schema.graphql",
+  response_derives = "Clone,Debug,PartialEq,Deserialize"
 );
 
-// Create a structure that matches the JSON structure that you'll use for your configuration
+// The input query to deploy the function with: `input.graphql` pointed at the configuration metafield
+// chosen when building. The types above are generated from the tracked query, since the metafield it
+// reads doesn't change them.
+#[cfg_attr(not(test), allow(dead_code))]
+pub(crate) const INPUT_QUERY: &str = include_str!(concat!(env!("OUT_DIR"), "/input.graphql"));
+
+// Create a structure that matches the JSON structure that you'll use for your configuration.
+// A legacy flat configuration like `{"paymentMethodName": ..., "cartTotal": ...}` is read as a single rule.
+#[derive(Serialize, Deserialize, PartialEq)]
+#[serde(from = "RawConfiguration")]
+pub(crate) struct Configuration {
+  // Always the current version once parsed, since older configurations are migrated first
+  version: u64,
+  // Turns the whole customization off without removing its metafield
+  enabled: bool,
+  rules: Vec<PaymentRule>,
+  // Log the operations instead of applying them, for trying out rules against real carts
+  dry_run: bool,
+  // Log how long each phase of the run took
+  profile: bool,
+  // How the cart total is rounded to its currency's decimal places before it's compared to thresholds
+  rounding: money::RoundingMode,
+  // The kinds of operations the store's function runtime accepts, all of them by default. Runtimes on
+  // older API versions reject a whole result containing an operation kind they don't support.
+  supported_operations: HashSet<OperationKind>,
+  // Reject configurations with unknown keys instead of ignoring them, to catch typos
+  strict: bool,
+  // Compare payment method names without their accents, spaces and punctuation, so "Carte Bancaire"
+  // matches "Carte-Bancaire". Applies to the configured and allowed names of every rule, not to name
+  // regexes.
+  normalize_names: bool,
+  // The unknown keys, like "cartTotl" or "rules[1].renameTO", sorted
+  unknown_fields: Vec<String>
+}
+
+// No rules, so nothing is customized
+impl Default for Configuration {
+  fn default() -> Self {
+    Configuration {
+      version: CONFIGURATION_VERSION,
+      enabled: true,
+      rules: vec![],
+      dry_run: false,
+      profile: false,
+      rounding: money::RoundingMode::default(),
+      supported_operations: OperationKind::ALL.into(),
+      strict: false,
+      normalize_names: false,
+      unknown_fields: vec![]
+    }
+  }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all(deserialize = "camelCase"))]
+struct RawConfiguration {
+  #[serde(default = "default_enabled")]
+  enabled: bool,
+  #[serde(default)]
+  rules: Vec<PaymentRule>,
+  #[serde(default)]
+  dry_run: bool,
+  #[serde(default)]
+  profile: bool,
+  #[serde(default)]
+  rounding: money::RoundingMode,
+  #[serde(default = "default_supported_operations")]
+  supported_operations: HashSet<OperationKind>,
+  #[serde(default)]
+  strict: bool,
+  #[serde(default)]
+  normalize_names: bool,
+  #[serde(flatten)]
+  legacy_rule: PaymentRule
+}
+
+impl From<RawConfiguration> for Configuration {
+  fn from(raw: RawConfiguration) -> Self {
+    let mut unknown_fields: Vec<_> = raw.legacy_rule.unknown_fields.keys().cloned().collect();
+    for (index, rule) in raw.rules.iter().enumerate() {
+      unknown_fields.extend(rule.unknown_fields.keys().map(|key| format!("rules[{}].{}", index, key)));
+    }
+    unknown_fields.sort();
+    let rules = if raw.rules.is_empty() { vec![raw.legacy_rule] } else { raw.rules };
+    Configuration {
+      version: CONFIGURATION_VERSION,
+      enabled: raw.enabled,
+      rules,
+      dry_run: raw.dry_run,
+      profile: raw.profile,
+      rounding: raw.rounding,
+      supported_operations: raw.supported_operations,
+      strict: raw.strict,
+      normalize_names: raw.normalize_names,
+      unknown_fields
+    }
+  }
+}
+
+fn default_enabled() -> bool {
+  true
+}
+
+fn default_supported_operations() -> HashSet<OperationKind> {
+  OperationKind::ALL.into()
+}
+
+// A set of cart conditions and what to do with the payment methods matching the configured names
 #[derive(Serialize, Deserialize, Default, PartialEq)]
 #[serde(rename_all(deserialize = "camelCase"))]
-struct Configuration {
-  payment_method_name: String,
-  cart_total: f64
+struct PaymentRule {
+  // Names the rule in the logs of the operations it produces, like "cod-over-500"
+  #[serde(default)]
+  id: Option<String>,
+  // Accepts either a single name or a list of names
+  #[serde(default, deserialize_with = "shopify_config::deserialize_one_or_many")]
+  payment_method_names: Vec<String>,
+  // Accepts either a JSON number, a decimal string like "100.00" or a money object like
+  // {"amount": "100.00", "currencyCode": "USD"}, whose currency works like `currency_code`. Used for
+  // any currency missing from `cart_total_by_currency`. A rule without any cart total that sets one of
+  // the other thresholds (weight, quantity, percentage of subtotal or shipping countries) is gated on
+  // those alone.
+  #[serde(default)]
+  cart_total: Option<MoneyInput>,
+  // How the cart total is compared with the cart total threshold, at least the threshold by default
+  #[serde(default)]
+  comparison: Comparison,
+  // Fire exactly when the comparison doesn't hold, like hiding a method unless the cart total is at
+  // least the threshold
+  #[serde(default)]
+  negate: bool,
+  // Thresholds keyed by currency code, like {"EUR": "90.00", "GBP": 80}
+  #[serde(default, deserialize_with = "money::deserialize_decimal_map")]
+  cart_total_by_currency: HashMap<String, money::Decimal>,
+  // Optional inclusive upper bound, so methods are only hidden for carts within [cart_total, cart_total_max]
+  #[serde(default, deserialize_with = "money::deserialize_optional_decimal")]
+  cart_total_max: Option<money::Decimal>,
+  // Only fire when the cart's total weight in grams meets or exceeds this
+  #[serde(default)]
+  min_cart_weight_grams: Option<f64>,
+  // Only fire when the quantities of all cart lines add up to at least this
+  #[serde(default)]
+  min_total_quantity: Option<i32>,
+  // Only fire when the buyer has every one of these customer tags. Only the tags in `CUSTOMER_TAGS` are
+  // queried, and anonymous buyers have no tags.
+  #[serde(default)]
+  required_customer_tags: Vec<String>,
+  // Only fire when the cart ships to one of these country codes, like ["FR", "BE"]
+  #[serde(default, deserialize_with = "shopify_config::deserialize_one_or_many")]
+  hide_for_countries: Vec<String>,
+  // Only fire when the cart ships outside these country codes, for methods that are only valid
+  // domestically. A country in both lists is hidden, so `hide_for_countries` takes precedence. Carts
+  // without a shipping country yet never fire on either list.
+  #[serde(default, deserialize_with = "shopify_config::deserialize_one_or_many")]
+  allow_for_countries: Vec<String>,
+  // Only fire when the cart total is at least this percentage of the subtotal, like 110.0 when
+  // taxes and shipping add 10% or more. Carts with a zero subtotal never fire.
+  #[serde(default)]
+  min_cart_total_pct_of_subtotal: Option<f64>,
+  // Only compare cart totals in this currency; any currency is compared when missing
+  #[serde(default)]
+  currency_code: Option<String>,
+  // Ignore case when comparing payment method names, unless explicitly turned off
+  #[serde(default = "default_case_insensitive")]
+  case_insensitive: bool,
+  // Target every payment method except the ones with these names, instead of the configured names.
+  // Compared like the configured names. An empty list targets nothing rather than every method.
+  #[serde(default)]
+  allowed_payment_method_names: Option<Vec<String>>,
+  // How the configured name is compared with each payment method's name
+  #[serde(default)]
+  match_mode: NameMatch,
+  // Also matches payment methods whose name matches this regular expression, like
+  // "^(carte bancaire|credit card)". Follows `case_insensitive` like the configured names.
+  #[serde(default)]
+  name_regex: Option<String>,
+  // Rename the matched payment methods to this name instead of hiding them
+  #[serde(default)]
+  rename_to: Option<String>,
+  // Move the matched payment methods instead of hiding them, like "top", "bottom" or {"index": 2},
+  // where the bottom is the last of the payment methods
+  #[serde(default)]
+  move_to: Option<MoveTarget>,
+  // The original form of `move_to`, an index from the top of the list. Negative indices are clamped to 0.
+  #[serde(default)]
+  move_to_index: Option<i32>,
+  // Only customize the first targeted payment method in checkout order, for stores with several
+  // payment methods of the same name that should keep all but one
+  #[serde(default)]
+  first_match_only: bool,
+  // Keys this rule doesn't know, like a misspelled `cartTotl`, which strict configurations reject. The
+  // unknown top-level keys of a configuration end up in its flat rule.
+  #[serde(flatten, skip_serializing)]
+  unknown_fields: HashMap<String, serde_json::Value>,
+  // The `id`, or the rule's position like "rules[1]" without one, filled in by `identify`
+  #[serde(skip)]
+  log_id: String
+}
+
+fn default_case_insensitive() -> bool {
+  true
+}
+
+#[derive(Serialize, Deserialize, Default, PartialEq, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+enum NameMatch {
+  // The payment method's name contains the configured name
+  #[default]
+  Contains,
+  // The payment method's name is exactly the configured name
+  Exact
+}
+
+#[derive(Serialize, Deserialize, Default, PartialEq, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+enum Comparison {
+  // The cart total is at least the threshold
+  #[default]
+  GreaterOrEqual,
+  // The cart total is below the threshold, like small carts that can't pay by wire transfer
+  LessThan,
+  // The cart total is exactly the threshold
+  Equal
+}
+
+// A money amount in the configuration, optionally in a specific currency
+#[derive(Serialize, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+struct MoneyInput {
+  amount: money::Decimal,
+  currency_code: Option<String>
+}
+
+impl From<money::Decimal> for MoneyInput {
+  fn from(amount: money::Decimal) -> Self {
+    MoneyInput { amount, currency_code: None }
+  }
+}
+
+// Deserialize from either a bare amount or a money object, keeping the amount's parse errors
+impl<'de> Deserialize<'de> for MoneyInput {
+  fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct MoneyObject {
+      #[serde(deserialize_with = "money::deserialize_decimal")]
+      amount: money::Decimal,
+      #[serde(default)]
+      currency_code: Option<String>
+    }
+
+    let value = serde_json::Value::deserialize(deserializer)?;
+    if value.is_object() {
+      let MoneyObject { amount, currency_code } = serde_json::from_value(value).map_err(serde::de::Error::custom)?;
+      Ok(MoneyInput { amount, currency_code })
+    } else {
+      money::deserialize_decimal(value).map(MoneyInput::from).map_err(serde::de::Error::custom)
+    }
+  }
+}
+
+impl Comparison {
+  fn holds(self, total: money::Decimal, threshold: money::Decimal) -> bool {
+    match self {
+      Comparison::GreaterOrEqual => total >= threshold,
+      Comparison::LessThan => total < threshold,
+      Comparison::Equal => total == threshold
+    }
+  }
+}
+
+// The parts of the cart that payment rules are evaluated against
+struct CartContext {
+  total: money::Decimal,
+  subtotal: Option<money::Decimal>,
+  currency_code: Option<String>,
+  weight_grams: f64,
+  total_quantity: i64,
+  customer_tags: HashSet<String>,
+  // The country codes of the cart's delivery addresses, empty until the buyer enters one
+  shipping_countries: HashSet<String>
+}
+
+// The customer tags that rules can require, each queried in `input.graphql` under an alias named after
+// the tag. A tag has to be added both here and to the query.
+const CUSTOMER_TAGS: &[(&str, HasTag)] = &[
+  ("wholesale", |customer| customer.wholesale),
+];
+
+type HasTag = fn(&input::InputCartBuyerIdentityCustomer) -> bool;
+
+// The queried tags that the buyer has, if they're a logged in customer
+fn customer_tags(buyer_identity: Option<&input::InputCartBuyerIdentity>) -> HashSet<String> {
+  let customer = buyer_identity.and_then(|buyer_identity| buyer_identity.customer.as_ref());
+  match customer {
+    Some(customer) => CUSTOMER_TAGS
+      .iter()
+      .filter(|(_, has_tag)| has_tag(customer))
+      .map(|(tag, _)| tag.to_string())
+      .collect(),
+    None => HashSet::new()
+  }
+}
+
+// The total weight of the cart's lines in grams. Lines without weight data count as weightless.
+fn cart_weight_grams(lines: &[input::InputCartLines]) -> f64 {
+  lines
+    .iter()
+    .map(|line| match &line.merchandise {
+      input::InputCartLinesMerchandise::ProductVariant(variant) => {
+        let grams_per_unit = match variant.weight_unit {
+          input::WeightUnit::GRAMS => 1.0,
+          input::WeightUnit::KILOGRAMS => 1000.0,
+          input::WeightUnit::OUNCES => 28.349523125,
+          input::WeightUnit::POUNDS => 453.59237,
+          input::WeightUnit::Other(_) => 0.0
+        };
+        variant.weight.unwrap_or(0.0) * grams_per_unit * line.quantity as f64
+      }
+      _ => 0.0
+    })
+    .sum()
 }
 
 // Parse the JSON metafield value using serde
 impl Configuration {
-  fn from_str(value: &str) -> Self {
-    serde_json::from_str(value).expect("Unable to parse configuration value from metafield")
+  // Migrate older configuration versions before deserializing. A newer version than this function
+  // knows about falls back to the default configuration, which leaves every payment method as it is.
+  fn from_str(value: &str) -> std::result::Result<Self, serde_json::Error> {
+    let mut value: serde_json::Value = shopify_config::parse_config(value)?;
+    match shopify_config::config_version(&value) {
+      1 => shopify_config::rename_rule_keys(&mut value, &[("paymentMethodName", "paymentMethodNames")]),
+      CONFIGURATION_VERSION => {}
+      version => {
+        log(Level::Warn, "Unsupported configuration version, falling back to the default configuration", &[
+          ("version", json!(version)),
+          ("supported_version", json!(CONFIGURATION_VERSION)),
+        ]);
+        return Ok(Configuration::default());
+      }
+    }
+    // The version was only needed to migrate the configuration, so it isn't an unknown key
+    if let Some(object) = value.as_object_mut() {
+      object.remove("version");
+    }
+    let mut config: Configuration = serde_json::from_value(value)?;
+    if config.strict && !config.unknown_fields.is_empty() {
+      let message = format!("unknown fields in strict configuration: {}", config.unknown_fields.join(", "));
+      return Err(serde::de::Error::custom(message));
+    }
+    config.prepare();
+    Ok(config)
+  }
+
+  // Fill in what the rules derive from their parsed fields: ids and normalized names and countries
+  fn prepare(&mut self) {
+    self.rules.iter_mut().enumerate().for_each(|(index, rule)| rule.identify(index));
+    self.rules.iter_mut().for_each(PaymentRule::normalize);
+  }
+
+  // Check the invariants serde can't express, collecting every problem so they can all be fixed at once
+  fn validate(&self) -> std::result::Result<(), Vec<String>> {
+    let mut errors = vec![];
+    if self.supported_operations.is_empty() {
+      errors.push("supportedOperations must not be empty".to_string());
+    }
+    for (index, rule) in self.rules.iter().enumerate() {
+      rule.validate(&format!("rules[{}]", index), &mut errors);
+      // A name left empty would be contained in every payment method's name
+      let mut names = rule.payment_method_names.iter().chain(rule.allowed_payment_method_names.iter().flatten());
+      if self.normalize_names && names.any(|name| shopify_config::normalize_name(name).is_empty()) {
+        errors.push(format!("rules[{}]: names must have letters or digits when normalizeNames is set", index));
+      }
+    }
+    if errors.is_empty() { Ok(()) } else { Err(errors) }
+  }
+}
+
+impl PaymentRule {
+  // Name the rule in logs by its id, falling back to its position among the configuration's rules
+  fn identify(&mut self, index: usize) {
+    self.log_id = self.id.clone().unwrap_or_else(|| format!("rules[{}]", index));
+  }
+
+  // Normalize the whitespace of the allow-list, like serde already does for
+  // `payment_method_names`. Payment method names are normalized the same way before they're compared.
+  // Country codes are compared in upper case, as Shopify reports them.
+  fn normalize(&mut self) {
+    for countries in [&mut self.hide_for_countries, &mut self.allow_for_countries] {
+      for country in countries.iter_mut() {
+        *country = country.to_uppercase();
+      }
+    }
+    if let Some(allowed) = &mut self.allowed_payment_method_names {
+      *allowed = allowed.iter()
+        .map(|name| shopify_config::normalize_whitespace(name).into_owned())
+        .filter(|name| !name.is_empty())
+        .collect();
+    }
+  }
+
+  fn validate(&self, path: &str, errors: &mut Vec<String>) {
+    if self.id.as_deref().is_some_and(str::is_empty) {
+      errors.push(format!("{}: id must not be empty", path));
+    }
+    // Shopify only reports the tags the query asks about, so any other tag would never be met
+    for tag in &self.required_customer_tags {
+      if !CUSTOMER_TAGS.iter().any(|(queried, _)| queried == tag) {
+        let supported: Vec<_> = CUSTOMER_TAGS.iter().map(|(queried, _)| *queried).collect();
+        errors.push(format!(
+          "{}: requiredCustomerTags {:?} isn't queried, the supported tags are {}",
+          path, tag, supported.join(", ")
+        ));
+      }
+    }
+    let negative_totals = self.cart_total.iter()
+      .map(|cart_total| &cart_total.amount)
+      .chain(self.cart_total_by_currency.values())
+      .chain(&self.cart_total_max)
+      .any(|total| total.is_sign_negative());
+    if negative_totals {
+      errors.push(format!("{}: cart totals must not be negative", path));
+    }
+    if let (Some(MoneyInput { currency_code: Some(currency), .. }), Some(currency_code)) = (&self.cart_total, &self.currency_code) {
+      if currency != currency_code {
+        errors.push(format!("{}: the cartTotal currency must match currencyCode", path));
+      }
+    }
+    let cart_total = self.cart_total.as_ref().map(|cart_total| cart_total.amount);
+    if let (Some(cart_total), Some(cart_total_max)) = (cart_total, self.cart_total_max) {
+      if cart_total_max < cart_total {
+        errors.push(format!("{}: cartTotalMax must not be below cartTotal", path));
+      }
+    }
+    let valid_threshold = |threshold: f64| threshold.is_finite() && threshold >= 0.0;
+    if self.min_cart_weight_grams.is_some_and(|threshold| !valid_threshold(threshold)) {
+      errors.push(format!("{}: minCartWeightGrams must be a non-negative number", path));
+    }
+    if self.min_cart_total_pct_of_subtotal.is_some_and(|threshold| !valid_threshold(threshold)) {
+      errors.push(format!("{}: minCartTotalPctOfSubtotal must be a non-negative number", path));
+    }
+    if self.min_total_quantity.is_some_and(|threshold| threshold < 0) {
+      errors.push(format!("{}: minTotalQuantity must not be negative", path));
+    }
+    if let Some(Err(error)) = self.compile_name_regex() {
+      errors.push(format!("{}: nameRegex is invalid: {}", path, error));
+    }
+    if self.rename_to.as_deref().is_some_and(str::is_empty) {
+      errors.push(format!("{}: renameTo must not be empty", path));
+    }
+    if self.negate && self.cart_total.is_none() && self.cart_total_by_currency.is_empty() {
+      errors.push(format!("{}: negate only applies with a cart total", path));
+    }
+  }
+
+  // Whether the cart meets this rule's conditions, logging why not when it doesn't
+  fn fires(&self, cart: &CartContext) -> bool {
+    if self.allowed_payment_method_names.as_ref().is_some_and(Vec::is_empty) {
+      log(Level::Warn, "Allowed payment method names are empty, no need to hide the payment methods.", &[]);
+      return false;
+    }
+
+    // The configured cart total is only meaningful in the configured currency
+    if let Some(currency_code) = self.currency() {
+      if cart.currency_code.as_deref() != Some(currency_code) {
+        log(Level::Info, "Cart currency doesn't match the configured currency, no need to hide the payment method.", &[
+          ("cart_currency", json!(cart.currency_code)),
+          ("currency_code", json!(currency_code)),
+        ]);
+        return false;
+      }
+    }
+
+    if !self.required_customer_tags.iter().all(|tag| cart.customer_tags.contains(tag)) {
+      log(Level::Info, "Buyer doesn't have the required customer tags, no need to hide the payment method.", &[
+        ("required_customer_tags", json!(self.required_customer_tags)),
+      ]);
+      return false;
+    }
+
+    let targets_countries = !self.hide_for_countries.is_empty() || !self.allow_for_countries.is_empty();
+    if targets_countries && !self.targets_shipping_countries(&cart.shipping_countries) {
+      log(Level::Info, "Cart doesn't ship to a targeted country, no need to hide the payment method.", &[
+        ("shipping_countries", json!(cart.shipping_countries)),
+      ]);
+      return false;
+    }
+
+    if let Some(min_cart_weight_grams) = self.min_cart_weight_grams {
+      if cart.weight_grams < min_cart_weight_grams {
+        log(Level::Info, "Cart weight is not high enough, no need to hide the payment method.", &[
+          ("cart_weight_grams", json!(cart.weight_grams)),
+          ("threshold", json!(min_cart_weight_grams)),
+        ]);
+        return false;
+      }
+    }
+
+    if let Some(min_total_quantity) = self.min_total_quantity {
+      if cart.total_quantity < i64::from(min_total_quantity) {
+        log(Level::Info, "Cart quantity is not high enough, no need to hide the payment method.", &[
+          ("total_quantity", json!(cart.total_quantity)),
+          ("threshold", json!(min_total_quantity)),
+        ]);
+        return false;
+      }
+    }
+
+    if let Some(min_pct) = self.min_cart_total_pct_of_subtotal {
+      let pct = cart.subtotal
+        .filter(|subtotal| !subtotal.is_zero())
+        .map(|subtotal| cart.total * money::Decimal::ONE_HUNDRED / subtotal);
+      let threshold = money::Decimal::try_from(min_pct).ok();
+      match (pct, threshold) {
+        (Some(pct), Some(threshold)) if pct >= threshold => {}
+        _ => {
+          log(Level::Info, "Cart total is not a high enough percentage of the subtotal, no need to hide the payment method.", &[
+            ("cart_total", json!(cart.total)),
+            ("subtotal", json!(cart.subtotal)),
+            ("threshold", json!(min_pct)),
+          ]);
+          return false;
+        }
+      }
+    }
+
+    let other_thresholds_only = (self.min_cart_weight_grams.is_some()
+      || self.min_total_quantity.is_some()
+      || self.min_cart_total_pct_of_subtotal.is_some()
+      || targets_countries)
+      && self.cart_total.is_none()
+      && self.cart_total_by_currency.is_empty();
+    if !other_thresholds_only {
+      let threshold = match self.cart_total_for(cart.currency_code.as_deref()) {
+        Some(threshold) => threshold,
+        None => {
+          log(Level::Info, "No cart total is configured for the cart currency, no need to hide the payment method.", &[
+            ("cart_currency", json!(cart.currency_code)),
+          ]);
+          return false;
+        }
+      };
+      if self.comparison.holds(cart.total, threshold) == self.negate {
+        let message = if self.negate {
+          "Cart total meets the negated comparison, no need to hide the payment method."
+        } else {
+          match self.comparison {
+            Comparison::GreaterOrEqual => "Cart total is not high enough, no need to hide the payment method.",
+            Comparison::LessThan => "Cart total is not low enough, no need to hide the payment method.",
+            Comparison::Equal => "Cart total is not the configured total, no need to hide the payment method."
+          }
+        };
+        log(Level::Info, message, &[
+          ("cart_total", json!(cart.total)),
+          ("threshold", json!(threshold)),
+        ]);
+        return false;
+      }
+    }
+    if self.cart_total_max.is_some_and(|cart_total_max| cart.total > cart_total_max) {
+      log(Level::Info, "Cart total is above the maximum, no need to hide the payment method.", &[
+        ("cart_total", json!(cart.total)),
+        ("threshold", json!(self.cart_total_max)),
+      ]);
+      return false;
+    }
+    true
+  }
+
+  // Whether the cart ships to a hidden country, or to a country outside the allowed ones
+  fn targets_shipping_countries(&self, shipping_countries: &HashSet<String>) -> bool {
+    let hidden = shipping_countries.iter().any(|country| self.hide_for_countries.contains(country));
+    let not_allowed = !self.allow_for_countries.is_empty()
+      && shipping_countries.iter().any(|country| !self.allow_for_countries.contains(country));
+    hidden || not_allowed
+  }
+
+  // The threshold for the cart's currency, falling back to the default cart total
+  fn cart_total_for(&self, currency_code: Option<&str>) -> Option<money::Decimal> {
+    currency_code
+      .and_then(|currency_code| self.cart_total_by_currency.get(currency_code))
+      .copied()
+      .or(self.cart_total.as_ref().map(|cart_total| cart_total.amount))
+  }
+
+  // The currency cart totals are compared in, from `currency_code` or the cart total's money object
+  fn currency(&self) -> Option<&str> {
+    self.currency_code.as_deref()
+      .or(self.cart_total.as_ref().and_then(|cart_total| cart_total.currency_code.as_deref()))
+  }
+
+  // The compiled `name_regex`, if one is configured
+  fn compile_name_regex(&self) -> Option<std::result::Result<Regex, regex::Error>> {
+    let pattern = self.name_regex.as_ref()?;
+    Some(RegexBuilder::new(pattern).case_insensitive(self.case_insensitive).build())
+  }
+
+  // Whether this rule targets a payment method: every method outside the allow-list when one is
+  // configured, otherwise the methods matching the configured names or the compiled name regex
+  fn targets(&self, name: &str, name_regex: Option<&Regex>, normalize_names: bool) -> bool {
+    if let Some(allowed) = &self.allowed_payment_method_names {
+      return !self.matches_any(name, allowed, normalize_names);
+    }
+    name_regex.is_some_and(|name_regex| name_regex.is_match(name))
+      || self.matches_any(name, &self.payment_method_names, normalize_names)
+  }
+
+  // Whether a payment method's name matches any of the given names, according to the match mode. With
+  // `normalize_names`, both names are stripped of their accents and punctuation first.
+  fn matches_any(&self, name: &str, names: &[String], normalize_names: bool) -> bool {
+    let name = if normalize_names {
+      Cow::Owned(shopify_config::normalize_name(name))
+    } else {
+      shopify_config::normalize_whitespace(name)
+    };
+    names.iter().any(|configured| {
+      let configured = if normalize_names {
+        Cow::Owned(shopify_config::normalize_name(configured))
+      } else {
+        Cow::Borrowed(configured.as_str())
+      };
+      let (name, configured) = if self.case_insensitive {
+        (name.to_lowercase(), configured.to_lowercase())
+      } else {
+        (name.to_string(), configured.to_string())
+      };
+      match self.match_mode {
+        NameMatch::Contains => name.contains(&configured),
+        NameMatch::Exact => name == configured
+      }
+    })
+  }
+
+  // Renaming takes precedence over moving, and hiding is the default. Moves are placed among
+  // `method_count` payment methods.
+  fn operation_for(&self, method: &input::InputPaymentMethods, method_count: usize) -> output::Operation {
+    let move_to = self.move_to.or(self.move_to_index.map(MoveTarget::Index));
+    match (&self.rename_to, move_to) {
+      (Some(name), _) => output::Operation::rename(output::RenameOperation {
+        payment_method_id: method.id.to_string(),
+        name: name.to_string()
+      }),
+      (None, Some(target)) => output::Operation::move_to(output::MoveOperation {
+        payment_method_id: method.id.to_string(),
+        index: target.index(method_count)
+      }),
+      (None, None) => output::Operation::hide(output::HideOperation {
+        payment_method_id: method.id.to_string()
+      })
+    }
+  }
+}
+
+// Shopify applies one action per operation, so each constructor leaves the other actions unset
+impl output::Operation {
+  fn hide(hide: output::HideOperation) -> Self {
+    output::Operation { hide: Some(hide), move_: None, rename: None }
+  }
+
+  fn move_to(move_: output::MoveOperation) -> Self {
+    output::Operation { hide: None, move_: Some(move_), rename: None }
+  }
+
+  fn rename(rename: output::RenameOperation) -> Self {
+    output::Operation { hide: None, move_: None, rename: Some(rename) }
+  }
+}
+
+// Operations of the same kind for the same payment method conflict, so only the first is kept. An
+// operation without an action has no key.
+fn operation_key(operation: &output::Operation) -> Option<(OperationKind, &str)> {
+  match operation {
+    output::Operation { hide: Some(hide), .. } => Some((OperationKind::Hide, &hide.payment_method_id)),
+    output::Operation { move_: Some(move_), .. } => Some((OperationKind::Move, &move_.payment_method_id)),
+    output::Operation { rename: Some(rename), .. } => Some((OperationKind::Rename, &rename.payment_method_id)),
+    _ => None
+  }
+}
+
+// Log which rule produced an operation, so operators can tell which of several firing rules caused it
+fn log_rule_operation(rule: &PaymentRule, operation: &output::Operation) {
+  if let Some((kind, id)) = operation_key(operation) {
+    log(Level::Info, "Rule produced an operation.", &[
+      ("rule", json!(rule.log_id)),
+      ("kind", json!(kind)),
+      ("payment_method_id", json!(id)),
+    ]);
+  }
+}
+
+// Write a structured log entry to STDERR, which shows up in the function's run logs
+fn log(level: Level, message: &str, fields: &[(&str, serde_json::Value)]) {
+  let entry = logging::entry(level, message, fields);
+  #[cfg(test)]
+  LOGS.with(|logs| logs.borrow_mut().push(entry.clone()));
+  eprintln!("{}", entry);
+}
+
+// Write a metric entry to STDERR with the log entries, which dashboards aggregate across runs
+fn metric(name: &str, values: &[(&str, serde_json::Value)]) {
+  let entry = logging::metric(name, values);
+  #[cfg(test)]
+  METRICS.with(|metrics| metrics.borrow_mut().push(entry.clone()));
+  eprintln!("{}", entry);
+}
+
+// Entries logged and metrics written by the current test thread
+#[cfg(test)]
+thread_local! {
+  static LOGS: std::cell::RefCell<Vec<String>> = const { std::cell::RefCell::new(Vec::new()) };
+  static METRICS: std::cell::RefCell<Vec<String>> = const { std::cell::RefCell::new(Vec::new()) };
+}
+
+// Environment variable holding a configuration that supersedes the metafield, for trying out staging
+// configurations locally
+const CONFIG_OVERRIDE_VARIABLE: &str = "FUNCTION_CONFIG_OVERRIDE";
+
+// The configuration override is only compiled into tests and builds with the `config-override`
+// feature, so deployed functions always read the metafield
+#[cfg(any(test, feature = "config-override"))]
+fn config_override() -> Option<String> {
+  // Tests run in parallel within one process, so only the threads opting in read the variable
+  #[cfg(test)]
+  if !READ_CONFIG_OVERRIDE.with(|read| read.get()) {
+    return None;
+  }
+  std::env::var(CONFIG_OVERRIDE_VARIABLE).ok()
+}
+
+#[cfg(not(any(test, feature = "config-override")))]
+fn config_override() -> Option<String> {
+  None
+}
+
+// Whether the current test thread reads the configuration override
+#[cfg(test)]
+thread_local! {
+  static READ_CONFIG_OVERRIDE: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+// Get the configuration from the metafield on your function owner, or None when there's no usable
+// configuration, logging what was missing
+pub(crate) fn safe_config(input: &input::ResponseData) -> Option<Configuration> {
+  let config_override = config_override();
+  let value = match (&config_override, &input.payment_customization.metafield) {
+    (Some(value), _) => {
+      log(Level::Warn, "Using the configuration from the environment instead of the metafield.", &[
+        ("variable", json!(CONFIG_OVERRIDE_VARIABLE)),
+      ]);
+      value
+    }
+    (None, Some(input::InputPaymentCustomizationMetafield { value })) => value,
+    (None, None) => {
+      log(Level::Info, "No configuration metafield, no need to customize payment methods.", &[
+        ("field", json!("paymentCustomization.metafield")),
+      ]);
+      return None;
+    }
+  };
+  match Configuration::from_str(value) {
+    Ok(config) => match config.validate() {
+      Ok(()) => Some(config),
+      Err(errors) => {
+        log(Level::Error, "Invalid configuration value in metafield", &[
+          ("errors", json!(errors)),
+        ]);
+        None
+      }
+    },
+    // A malformed configuration shouldn't break checkout, so leave the payment methods as they are
+    Err(error) => {
+      log(Level::Error, "Unable to parse configuration value from metafield", &[
+        ("error", json!(error.to_string())),
+      ]);
+      None
+    }
   }
 }
 
 // Use the shopify_function crate to declare your function entrypoint
 #[shopify_function]
-fn function(input: input::ResponseData) -> Result<output::FunctionResult> {
-  let no_changes = output::FunctionResult { operations: vec![] };
+pub(crate) fn function(input: input::ResponseData) -> Result<output::FunctionResult> {
+  let result = customize(&input);
+  // Every run reports how many operations of each kind it produced, including runs that change nothing
+  let kinds = result.operations.iter().filter_map(operation_key).map(|(kind, _)| kind);
+  metric("operations", &logging::operation_counts(kinds));
+  Ok(result)
+}
 
-  // Get the configuration from the metafield on your function owner
-  let config = match input.payment_customization.metafield {
-    Some(input::InputPaymentCustomizationMetafield { value }) => Configuration::from_str(&value),
-    None => return Ok(no_changes),
+// Parse the configuration and customize the payment methods with it
+fn customize(input: &input::ResponseData) -> output::FunctionResult {
+  // Reading the clock is cheap, so the parse is always timed but only reported when profiling
+  let started = Instant::now();
+  let config = match safe_config(input) {
+    Some(config) => config,
+    None => return output::FunctionResult { operations: vec![] },
   };
+  if !config.enabled {
+    log(Level::Info, "Customization is disabled, no need to customize payment methods.", &[]);
+    return output::FunctionResult { operations: vec![] };
+  }
+
+  let mut profiler = Profiler::since(started, config.profile);
+  profiler.phase("parse");
+
+  apply_profiled(&config, input, profiler)
+}
+
+// Decide how to customize the payment methods for a parsed configuration, without any of the function's I/O.
+// Only tests, benchmarks and the evaluate tool start without a parse phase.
+#[cfg_attr(not(test), allow(dead_code))]
+pub(crate) fn apply(config: &Configuration, input: &input::ResponseData) -> output::FunctionResult {
+  apply_profiled(config, input, Profiler::start(config.profile))
+}
+
+// Like `apply`, timing the filter and build phases with a profiler that may already have timed the parse
+fn apply_profiled(config: &Configuration, input: &input::ResponseData, mut profiler: Profiler) -> output::FunctionResult {
+  let no_changes = output::FunctionResult { operations: vec![] };
 
   // Use the configured cart total instead of a hardcoded value
-  let cart_total = input.cart.cost.total_amount.amount.parse::<f64>().unwrap();
-  if cart_total < config.cart_total {
-    eprintln!("Cart total is not high enough, no need to hide the payment method.");
-    return Ok(no_changes);
+  let total = match money::parse_decimal(&input.cart.cost.total_amount.amount) {
+    Some(total) => total,
+    None => {
+      log(Level::Warn, "Unable to parse cart total, no need to hide the payment method.", &[
+        ("cart_total", json!(input.cart.cost.total_amount.amount)),
+      ]);
+      return no_changes;
+    }
+  };
+  let currency_code = shopify_config::enum_value(&input.cart.cost.total_amount.currency_code);
+  let decimal_places = currency_code.as_deref().map_or(2, money::currency_decimal_places);
+  let cart = CartContext {
+    total: config.rounding.round(total, decimal_places),
+    subtotal: money::parse_decimal(&input.cart.cost.subtotal_amount.amount),
+    currency_code,
+    weight_grams: cart_weight_grams(&input.cart.lines),
+    total_quantity: input.cart.lines.iter().map(|line| line.quantity).sum(),
+    customer_tags: customer_tags(input.cart.buyer_identity.as_ref()),
+    shipping_countries: input.cart.delivery_groups
+      .iter()
+      .filter_map(|group| group.delivery_address.as_ref())
+      .filter_map(|address| address.country_code.as_ref())
+      .filter_map(shopify_config::enum_value)
+      .collect()
+  };
+
+  // Compile each rule's name regex once. An invalid pattern would match the wrong payment methods, so
+  // leave them all as they are.
+  let mut name_regexes = Vec::with_capacity(config.rules.len());
+  for rule in &config.rules {
+    match rule.compile_name_regex().transpose() {
+      Ok(name_regex) => name_regexes.push(name_regex),
+      Err(error) => {
+        log(Level::Error, "Unable to compile the payment method name regex", &[
+          ("name_regex", json!(rule.name_regex)),
+          ("error", json!(error.to_string())),
+        ]);
+        return no_changes;
+      }
+    }
   }
 
-  // Use the configured payment method name instead of a hardcoded value
-  let hide_payment_method = input.payment_methods
+  // Hide, rename or move every payment method targeted by each rule the cart meets, one operation per
+  // payment method id even when names repeat. Rules targeting the same payment method don't produce
+  // duplicate operations.
+  let targeted: Vec<_> = config.rules
     .iter()
-    .find(|&method| method.name.contains(&config.payment_method_name.to_string()))
-    .map(|method| output::HideOperation {
-        payment_method_id: method.id.to_string()
-    });
+    .zip(&name_regexes)
+    .filter(|(rule, _)| rule.fires(&cart))
+    .flat_map(|(rule, name_regex)| {
+      input.payment_methods
+        .iter()
+        .filter(move |&method| rule.targets(&method.name, name_regex.as_ref(), config.normalize_names))
+        .take(if rule.first_match_only { 1 } else { usize::MAX })
+        .map(move |method| (rule, method))
+    })
+    .collect();
+  profiler.phase("filter");
 
-  Ok(output::FunctionResult { operations: vec![
-      output::Operation {
-        hide: hide_payment_method,
-        move_: None,
-        rename: None
-      }
-  ] })
+  let mut seen = HashSet::new();
+  let mut operations = targeted
+    .into_iter()
+    .map(|(rule, method)| (rule, rule.operation_for(method, input.payment_methods.len())))
+    .filter(|(_, operation)| {
+      operation_key(operation).is_some_and(|(kind, id)| seen.insert((kind, id.to_string())))
+    })
+    // Drop the operations the store's runtime doesn't support rather than having it reject them all
+    .filter(|(_, operation)| {
+      operation_key(operation).is_some_and(|(kind, id)| {
+        let supported = config.supported_operations.contains(&kind);
+        if !supported {
+          log(Level::Warn, "Operation is not supported, dropping it.", &[
+            ("kind", json!(kind)),
+            ("payment_method_id", json!(id)),
+          ]);
+        }
+        supported
+      })
+    })
+    .collect::<Vec<_>>();
+
+  // Never leave the buyer without a way to pay: when every payment method would be hidden, keep the
+  // first one in checkout order visible
+  let hidden: HashSet<_> = operations.iter()
+    .filter_map(|(_, operation)| operation.hide.as_ref().map(|hide| hide.payment_method_id.as_str()))
+    .collect();
+  let kept = input.payment_methods.first()
+    .filter(|_| input.payment_methods.iter().all(|method| hidden.contains(method.id.as_str())));
+  if let Some(kept) = kept {
+    log(Level::Warn, "Every payment method would be hidden, keeping the first one visible.", &[
+      ("payment_method_id", json!(kept.id)),
+      ("payment_method_name", json!(kept.name)),
+    ]);
+    operations.retain(|(_, operation)| operation.hide.as_ref().is_none_or(|hide| hide.payment_method_id != kept.id));
+  }
+  // Renames and hides go ahead of the moves, each by payment method id, so the same operations always come
+  // out in the same order. Moves keep rule order since each one places a method relative to the others.
+  sort_operations(&mut operations, |(_, operation)| operation_key(operation));
+  profiler.phase("build");
+
+  if let Some(fields) = profiler.fields() {
+    log(Level::Info, "Profiled the run.", &fields);
+  }
+
+  // Only the operations left after de-duplication, the unsupported kinds and keeping a method visible are
+  // attributed to their rules, including a dry run's
+  for (rule, operation) in &operations {
+    log_rule_operation(rule, operation);
+  }
+  let operations: Vec<_> = operations.into_iter().map(|(_, operation)| operation).collect();
+
+  if config.dry_run {
+    log(Level::Info, "Dry run, not customizing payment methods.", &[
+      ("operations", json!(operations)),
+    ]);
+    return no_changes;
+  }
+
+  output::FunctionResult { operations }
 }
 
 #[cfg(test)]
@@ -7,14 +7,1505 @@ fn test_result_contains_no_operations() -> Result<()> {
     function,
     r#"
       {
+        "cart": {
+          "deliveryGroups": [],
+          "lines": [],
+          "cost": {
+            "subtotalAmount": { "amount": "0.0" },
+            "totalAmount": { "amount": "0.0", "currencyCode": "USD" }
+          }
+        },
+        "paymentMethods": [],
         "paymentCustomization": {
           "metafield": null
         }
       }
     "#,
   )?;
-  let expected = crate::output::FunctionResult { operations: vec![] };
+  let expected = output::FunctionResult { operations: vec![] };
 
   assert_eq!(result, expected);
   Ok(())
 }
+
+// The structured log entries written by the current test, parsed back from JSON
+fn take_logs() -> Vec<serde_json::Value> {
+  LOGS.with(|logs| logs.take())
+    .iter()
+    .map(|entry| serde_json::from_str(entry).expect("log entries should be JSON"))
+    .collect()
+}
+
+// The logged entries other than the ones naming the rule behind each operation
+fn take_logs_without_rule_operations() -> Vec<serde_json::Value> {
+  take_logs().into_iter().filter(|entry| entry["message"] != "Rule produced an operation.").collect()
+}
+
+fn take_metrics() -> Vec<serde_json::Value> {
+  METRICS.with(|metrics| metrics.take())
+    .iter()
+    .map(|entry| serde_json::from_str(entry).expect("metric entries should be JSON"))
+    .collect()
+}
+
+#[test]
+fn test_invalid_configuration_returns_no_changes_and_logs() -> Result<()> {
+  let result = run_function_with_input(
+    function,
+    r#"
+      {
+        "cart": {
+          "deliveryGroups": [],
+          "lines": [],
+          "cost": {
+            "subtotalAmount": { "amount": "0.0" },
+            "totalAmount": { "amount": "150.0", "currencyCode": "USD" }
+          }
+        },
+        "paymentMethods": [{ "id": "1", "name": "Cash on Delivery" }],
+        "paymentCustomization": {
+          "metafield": { "value": "{\"paymentMethodName\": 42}" }
+        }
+      }
+    "#,
+  )?;
+
+  assert_eq!(result, output::FunctionResult { operations: vec![] });
+  let logs = take_logs();
+  assert_eq!(logs.len(), 1);
+  assert_eq!(logs[0]["level"], "error");
+  assert_eq!(logs[0]["message"], "Unable to parse configuration value from metafield");
+  assert!(logs[0]["error"].is_string());
+  Ok(())
+}
+
+#[test]
+fn test_unparseable_cart_total_returns_no_changes() -> Result<()> {
+  let result = run_function_with_input(
+    function,
+    r#"
+      {
+        "cart": {
+          "deliveryGroups": [],
+          "lines": [],
+          "cost": {
+            "subtotalAmount": { "amount": "0.0" },
+            "totalAmount": { "amount": "not-a-number", "currencyCode": "USD" }
+          }
+        },
+        "paymentMethods": [{ "id": "1", "name": "Cash on Delivery" }],
+        "paymentCustomization": {
+          "metafield": {
+            "value": "{\"paymentMethodName\": \"Cash on Delivery\", \"cartTotal\": 100}"
+          }
+        }
+      }
+    "#,
+  )?;
+
+  assert_eq!(result, output::FunctionResult { operations: vec![] });
+  assert_eq!(
+    take_logs(),
+    vec![json!({
+      "level": "warn",
+      "message": "Unable to parse cart total, no need to hide the payment method.",
+      "cart_total": "not-a-number"
+    })]
+  );
+  Ok(())
+}
+
+// A payment input for a $150.00 USD cart offering cash on delivery and credit card, without a configuration
+fn base_input() -> serde_json::Value {
+  serde_json::json!({
+    "cart": {
+      "deliveryGroups": [],
+      "lines": [],
+      "cost": {
+        "subtotalAmount": { "amount": "0.0" },
+        "totalAmount": { "amount": "150.00", "currencyCode": "USD" }
+      }
+    },
+    "paymentMethods": [{ "id": "1", "name": "Cash on Delivery" }, { "id": "2", "name": "Credit Card" }],
+    "paymentCustomization": { "metafield": null }
+  })
+}
+
+// Run the function with `input` and the given escaped `config` as the metafield value
+fn run_with_input(mut input: serde_json::Value, config: &str) -> Result<output::FunctionResult> {
+  let value: String = serde_json::from_str(&format!("\"{}\"", config))?;
+  input["paymentCustomization"]["metafield"] = serde_json::json!({ "value": value });
+  run_function_with_input(function, &input.to_string())
+}
+
+fn run_with_cart_total(amount: &str, config: &str) -> Result<output::FunctionResult> {
+  run_with_cart_total_in(amount, "USD", config)
+}
+
+fn run_with_cart_total_in(
+  amount: &str,
+  currency_code: &str,
+  config: &str,
+) -> Result<output::FunctionResult> {
+  let mut input = base_input();
+  input["cart"]["cost"]["totalAmount"] = serde_json::json!({ "amount": amount, "currencyCode": currency_code });
+  run_with_input(input, config)
+}
+
+fn hidden_cash_on_delivery() -> output::FunctionResult {
+  output::FunctionResult {
+    operations: vec![output::Operation::hide(output::HideOperation {
+      payment_method_id: "1".to_string(),
+    })],
+  }
+}
+
+#[test]
+fn test_cart_total_is_compared_exactly_to_the_cent() -> Result<()> {
+  for config in [
+    r#"{\"paymentMethodName\": \"Cash on Delivery\", \"cartTotal\": 100.00}"#,
+    r#"{\"paymentMethodName\": \"Cash on Delivery\", \"cartTotal\": \"100.00\"}"#,
+  ] {
+    assert_eq!(
+      run_with_cart_total("99.99", config)?,
+      output::FunctionResult { operations: vec![] }
+    );
+    assert_eq!(run_with_cart_total("100.00", config)?, hidden_cash_on_delivery());
+    assert_eq!(run_with_cart_total("100.01", config)?, hidden_cash_on_delivery());
+  }
+  Ok(())
+}
+
+#[test]
+fn test_payment_method_names_match_case_insensitively_by_default() -> Result<()> {
+  let config = r#"{\"paymentMethodName\": \"cash on delivery\", \"cartTotal\": 100}"#;
+
+  assert_eq!(run_with_cart_total("150.00", config)?, hidden_cash_on_delivery());
+  Ok(())
+}
+
+#[test]
+fn test_payment_method_names_match_exact_case_when_opted_out() -> Result<()> {
+  let config = r#"{\"paymentMethodName\": \"cash on delivery\", \"cartTotal\": 100, \"caseInsensitive\": false}"#;
+
+  assert_eq!(
+    run_with_cart_total("150.00", config)?,
+    output::FunctionResult { operations: vec![] }
+  );
+
+  let config = r#"{\"paymentMethodName\": \"Cash on Delivery\", \"cartTotal\": 100, \"caseInsensitive\": false}"#;
+
+  assert_eq!(run_with_cart_total("150.00", config)?, hidden_cash_on_delivery());
+  Ok(())
+}
+
+#[test]
+fn test_comparisons_at_the_cart_total_boundary() -> Result<()> {
+  let no_changes = || output::FunctionResult { operations: vec![] };
+  let config = |comparison: &str| {
+    format!(r#"{{\"paymentMethodName\": \"Cash on Delivery\", \"cartTotal\": \"100.00\", \"comparison\": \"{}\"}}"#, comparison)
+  };
+
+  let greater_or_equal = config("greaterOrEqual");
+  assert_eq!(run_with_cart_total("99.99", &greater_or_equal)?, no_changes());
+  assert_eq!(run_with_cart_total("100.00", &greater_or_equal)?, hidden_cash_on_delivery());
+  assert_eq!(run_with_cart_total("100.01", &greater_or_equal)?, hidden_cash_on_delivery());
+
+  let less_than = config("lessThan");
+  assert_eq!(run_with_cart_total("99.99", &less_than)?, hidden_cash_on_delivery());
+  assert_eq!(run_with_cart_total("100.00", &less_than)?, no_changes());
+  assert_eq!(run_with_cart_total("100.01", &less_than)?, no_changes());
+
+  let equal = config("equal");
+  assert_eq!(run_with_cart_total("99.99", &equal)?, no_changes());
+  assert_eq!(run_with_cart_total("100", &equal)?, hidden_cash_on_delivery());
+  assert_eq!(run_with_cart_total("100.01", &equal)?, no_changes());
+  Ok(())
+}
+
+#[test]
+fn test_negated_rule_fires_exactly_when_the_comparison_does_not() -> Result<()> {
+  let no_changes = || output::FunctionResult { operations: vec![] };
+  let config = |comparison: &str, negate: bool| {
+    format!(
+      r#"{{\"paymentMethodName\": \"Cash on Delivery\", \"cartTotal\": \"100.00\", \"comparison\": \"{}\", \"negate\": {}}}"#,
+      comparison, negate
+    )
+  };
+
+  for comparison in ["greaterOrEqual", "lessThan", "equal"] {
+    for total in ["99.99", "100.00", "100.01"] {
+      let fires = run_with_cart_total(total, &config(comparison, false))? == hidden_cash_on_delivery();
+      let expected = if fires { no_changes() } else { hidden_cash_on_delivery() };
+      assert_eq!(run_with_cart_total(total, &config(comparison, true))?, expected, "{} {}", comparison, total);
+    }
+  }
+  Ok(())
+}
+
+#[test]
+fn test_negated_rule_logs_why_it_did_not_fire() -> Result<()> {
+  let config = r#"{\"paymentMethodName\": \"Cash on Delivery\", \"cartTotal\": 100, \"negate\": true}"#;
+
+  assert_eq!(run_with_cart_total("150.00", config)?, output::FunctionResult { operations: vec![] });
+  assert_eq!(take_logs()[0]["message"], "Cart total meets the negated comparison, no need to hide the payment method.");
+  Ok(())
+}
+
+#[test]
+fn test_empty_rule_id_is_invalid() {
+  let config = Configuration::from_str(r#"{"paymentMethodName": "Cash", "cartTotal": 0, "id": ""}"#).unwrap();
+
+  assert_eq!(config.validate(), Err(vec!["rules[0]: id must not be empty".to_string()]));
+}
+
+#[test]
+fn test_negate_without_a_cart_total_is_invalid() {
+  let config = Configuration::from_str(r#"{"paymentMethodName": "Cash", "minTotalQuantity": 2, "negate": true}"#).unwrap();
+
+  assert_eq!(config.validate(), Err(vec!["rules[0]: negate only applies with a cart total".to_string()]));
+}
+
+#[test]
+fn test_less_than_comparison_logs_why_it_did_not_fire() -> Result<()> {
+  let config = r#"{\"paymentMethodName\": \"Cash on Delivery\", \"cartTotal\": 100, \"comparison\": \"lessThan\"}"#;
+
+  assert_eq!(run_with_cart_total("150.00", config)?, output::FunctionResult { operations: vec![] });
+  assert_eq!(take_logs()[0]["message"], "Cart total is not low enough, no need to hide the payment method.");
+  Ok(())
+}
+
+fn run_with_payment_methods(methods: &str, config: &str) -> Result<output::FunctionResult> {
+  let mut input = base_input();
+  input["paymentMethods"] = serde_json::from_str(methods)?;
+  run_with_input(input, config)
+}
+
+fn hidden_ids(result: &output::FunctionResult) -> Vec<String> {
+  result.operations
+    .iter()
+    .filter_map(|operation| operation.hide.as_ref())
+    .map(|hide| hide.payment_method_id.to_string())
+    .collect()
+}
+
+#[test]
+fn test_multi_byte_names_match_and_rename_whole() -> Result<()> {
+  let result = run_with_payment_methods(
+    r#"[{ "id": "1", "name": "Paiement À La Livraison" }, { "id": "2", "name": "代金引換" }]"#,
+    r#"{\"rules\": [{\"paymentMethodName\": \"paiement à la livraison\", \"cartTotal\": 0}, {\"paymentMethodName\": \"代金引換\", \"cartTotal\": 0, \"renameTo\": \"代金引換（手数料330円）\"}]}"#,
+  )?;
+
+  assert_eq!(hidden_ids(&result), vec!["1"]);
+  assert!(result.operations.contains(&output::Operation::rename(output::RenameOperation {
+    payment_method_id: "2".to_string(),
+    name: "代金引換（手数料330円）".to_string(),
+  })));
+  Ok(())
+}
+
+#[test]
+fn test_operation_counts_metric_matches_the_result() -> Result<()> {
+  let result = run_with_payment_methods(
+    r#"[{ "id": "1", "name": "Cash on Delivery" }, { "id": "2", "name": "Cheque" }, { "id": "3", "name": "Bank Deposit" }]"#,
+    r#"{\"rules\": [{\"paymentMethodNames\": [\"Cash on Delivery\", \"Cheque\"], \"cartTotal\": 100}, {\"paymentMethodName\": \"Bank Deposit\", \"cartTotal\": 0, \"renameTo\": \"Wire Transfer\"}]}"#,
+  )?;
+
+  let mut counts = json!({"metric": "operations", "hide": 0, "move": 0, "rename": 0});
+  for (kind, _) in result.operations.iter().filter_map(operation_key) {
+    counts[kind.name()] = json!(counts[kind.name()].as_u64().unwrap() + 1);
+  }
+
+  assert_eq!(take_metrics(), vec![counts]);
+  assert_eq!(result.operations.len(), 3);
+  Ok(())
+}
+
+#[test]
+fn test_operation_counts_metric_is_written_when_nothing_changes() -> Result<()> {
+  let result = run_with_payment_methods(r#"[{ "id": "1", "name": "Cheque" }]"#, r#"{\"paymentMethodName\": \"Gift Card\", \"cartTotal\": 100}"#)?;
+
+  assert_eq!(result, output::FunctionResult { operations: vec![] });
+  assert_eq!(take_metrics(), vec![json!({"metric": "operations", "hide": 0, "move": 0, "rename": 0})]);
+  Ok(())
+}
+
+#[test]
+fn test_rule_ids_are_logged_with_the_operations_they_produce() -> Result<()> {
+  run_with_payment_methods(
+    r#"[{ "id": "1", "name": "Cash on Delivery" }, { "id": "2", "name": "Bank Deposit" }]"#,
+    r#"{\"rules\": [{\"paymentMethodName\": \"Cash on Delivery\", \"cartTotal\": 500}, {\"id\": \"wire\", \"paymentMethodName\": \"Bank Deposit\", \"cartTotal\": 0, \"renameTo\": \"Wire Transfer\"}]}"#,
+  )?;
+
+  let logged: Vec<_> = take_logs()
+    .into_iter()
+    .filter(|entry| entry["message"] == "Rule produced an operation.")
+    .map(|entry| (entry["rule"].clone(), entry["kind"].clone(), entry["payment_method_id"].clone()))
+    .collect();
+  // The first rule doesn't fire for a cart of 150.00
+  assert_eq!(logged, vec![(json!("wire"), json!("rename"), json!("2"))]);
+  Ok(())
+}
+
+#[test]
+fn test_rules_are_not_logged_for_dropped_operations() -> Result<()> {
+  let config = r#"{\"rules\": [{\"id\": \"cod\", \"paymentMethodName\": \"Cash on Delivery\", \"cartTotal\": 0}, {\"id\": \"cash\", \"paymentMethodName\": \"Cash\", \"cartTotal\": 0}]}"#;
+
+  // The second rule's hide duplicates the first's
+  run_with_payment_methods(r#"[{ "id": "1", "name": "Card" }, { "id": "2", "name": "Cash on Delivery" }]"#, config)?;
+  let logged: Vec<_> = take_logs()
+    .into_iter()
+    .filter(|entry| entry["message"] == "Rule produced an operation.")
+    .map(|entry| (entry["rule"].clone(), entry["payment_method_id"].clone()))
+    .collect();
+  assert_eq!(logged, vec![(json!("cod"), json!("2"))]);
+
+  // The only payment method is kept visible
+  run_with_payment_methods(r#"[{ "id": "1", "name": "Cash on Delivery" }]"#, config)?;
+  assert!(take_logs().iter().all(|entry| entry["message"] != "Rule produced an operation."));
+  Ok(())
+}
+
+#[test]
+fn test_rules_without_an_id_are_logged_by_position() -> Result<()> {
+  run_with_payment_methods(
+    r#"[{ "id": "1", "name": "Cash on Delivery" }, { "id": "2", "name": "Bank Deposit" }]"#,
+    r#"{\"rules\": [{\"id\": \"cod\", \"paymentMethodName\": \"Cash on Delivery\", \"cartTotal\": 500}, {\"paymentMethodName\": \"Bank Deposit\", \"cartTotal\": 100}]}"#,
+  )?;
+
+  let logs = take_logs();
+  let logged: Vec<_> = logs.iter().filter(|entry| entry["message"] == "Rule produced an operation.").collect();
+  assert_eq!(logged.len(), 1);
+  assert_eq!(logged[0]["rule"], "rules[1]");
+  assert_eq!(logged[0]["kind"], "hide");
+  Ok(())
+}
+
+#[test]
+fn test_exact_match_mode_ignores_longer_names() -> Result<()> {
+  let result = run_with_payment_methods(
+    r#"[{ "id": "1", "name": "Visa Debit" }, { "id": "2", "name": "Visa" }]"#,
+    r#"{\"paymentMethodName\": \"visa\", \"cartTotal\": 100, \"matchMode\": \"exact\"}"#,
+  )?;
+
+  assert_eq!(hidden_ids(&result), vec!["2"]);
+  Ok(())
+}
+
+#[test]
+fn test_contains_match_mode_matches_longer_names() -> Result<()> {
+  let result = run_with_payment_methods(
+    r#"[{ "id": "1", "name": "Visa Debit" }, { "id": "2", "name": "Visa" }, { "id": "3", "name": "Cash" }]"#,
+    r#"{\"paymentMethodName\": \"Visa\", \"cartTotal\": 100, \"matchMode\": \"contains\"}"#,
+  )?;
+
+  assert_eq!(hidden_ids(&result), vec!["1", "2"]);
+  Ok(())
+}
+
+#[test]
+fn test_hides_every_method_matching_configured_names() -> Result<()> {
+  let methods = r#"[
+    { "id": "1", "name": "Cash on Delivery" },
+    { "id": "2", "name": "Cheque" },
+    { "id": "3", "name": "Credit Card" }
+  ]"#;
+
+  let result = run_with_payment_methods(
+    methods,
+    r#"{\"paymentMethodNames\": [\"Gift Card\"], \"cartTotal\": 100}"#,
+  )?;
+  assert_eq!(result, output::FunctionResult { operations: vec![] });
+
+  let result = run_with_payment_methods(
+    methods,
+    r#"{\"paymentMethodNames\": [\"cash\"], \"cartTotal\": 100}"#,
+  )?;
+  assert_eq!(hidden_ids(&result), vec!["1"]);
+
+  let result = run_with_payment_methods(
+    methods,
+    r#"{\"paymentMethodNames\": [\"cash\", \"cheque\"], \"cartTotal\": 100}"#,
+  )?;
+  assert_eq!(hidden_ids(&result), vec!["1", "2"]);
+  assert_eq!(result.operations.len(), 2);
+  Ok(())
+}
+
+#[test]
+fn test_cart_total_max_limits_hiding_to_a_range() -> Result<()> {
+  let config = r#"{\"paymentMethodName\": \"Cash on Delivery\", \"cartTotal\": 100, \"cartTotalMax\": \"500.00\"}"#;
+  let no_changes = output::FunctionResult { operations: vec![] };
+
+  assert_eq!(run_with_cart_total("99.99", config)?, no_changes);
+  assert_eq!(run_with_cart_total("250.00", config)?, hidden_cash_on_delivery());
+  assert_eq!(run_with_cart_total("500.00", config)?, hidden_cash_on_delivery());
+  assert_eq!(run_with_cart_total("500.01", config)?, no_changes);
+  Ok(())
+}
+
+#[test]
+fn test_missing_cart_total_max_has_no_upper_bound() -> Result<()> {
+  let config = r#"{\"paymentMethodName\": \"Cash on Delivery\", \"cartTotal\": 100}"#;
+
+  assert_eq!(run_with_cart_total("100000.00", config)?, hidden_cash_on_delivery());
+  Ok(())
+}
+
+#[test]
+fn test_rename_to_renames_instead_of_hiding() -> Result<()> {
+  let result = run_with_payment_methods(
+    r#"[{ "id": "1", "name": "Bank Deposit" }, { "id": "2", "name": "Credit Card" }]"#,
+    r#"{\"paymentMethodName\": \"Bank Deposit\", \"cartTotal\": 0, \"renameTo\": \"Bank Deposit (2–3 day processing)\"}"#,
+  )?;
+  let expected = output::FunctionResult {
+    operations: vec![output::Operation {
+      hide: None,
+      move_: None,
+      rename: Some(output::RenameOperation {
+        payment_method_id: "1".to_string(),
+        name: "Bank Deposit (2–3 day processing)".to_string(),
+      }),
+    }],
+  };
+
+  assert_eq!(result, expected);
+  Ok(())
+}
+
+fn moved_wallet(index: i64) -> output::FunctionResult {
+  output::FunctionResult {
+    operations: vec![output::Operation {
+      hide: None,
+      move_: Some(output::MoveOperation {
+        payment_method_id: "2".to_string(),
+        index,
+      }),
+      rename: None,
+    }],
+  }
+}
+
+#[test]
+fn test_move_to_index_moves_matched_methods() -> Result<()> {
+  let result = run_with_payment_methods(
+    r#"[{ "id": "1", "name": "Credit Card" }, { "id": "2", "name": "Shop Pay" }]"#,
+    r#"{\"paymentMethodName\": \"Shop Pay\", \"cartTotal\": 0, \"moveToIndex\": 1}"#,
+  )?;
+
+  assert_eq!(result, moved_wallet(1));
+  Ok(())
+}
+
+#[test]
+fn test_move_to_index_clamps_negative_index_to_top() -> Result<()> {
+  let result = run_with_payment_methods(
+    r#"[{ "id": "1", "name": "Credit Card" }, { "id": "2", "name": "Shop Pay" }]"#,
+    r#"{\"paymentMethodName\": \"Shop Pay\", \"cartTotal\": 0, \"moveToIndex\": -1}"#,
+  )?;
+
+  assert_eq!(result, moved_wallet(0));
+  Ok(())
+}
+
+#[test]
+fn test_cart_total_parses_from_a_bare_amount_or_a_money_object() {
+  for cart_total in [r#"100"#, r#""100.00""#, r#"{"amount": "100.00"}"#, r#"{"amount": 100, "currencyCode": "USD"}"#] {
+    let config = Configuration::from_str(&format!(r#"{{"paymentMethodName": "Cash", "cartTotal": {}}}"#, cart_total)).unwrap();
+
+    assert_eq!(config.rules[0].cart_total.as_ref().map(|cart_total| cart_total.amount), Some(money::Decimal::new(100, 0)), "{}", cart_total);
+  }
+  let error = Configuration::from_str(r#"{"paymentMethodName": "Cash", "cartTotal": {"amount": "abc", "currencyCode": "USD"}}"#);
+  assert!(error.is_err_and(|error| error.to_string().contains("\"abc\" is not a valid decimal amount")));
+}
+
+#[test]
+fn test_money_object_cart_total_only_fires_in_its_currency() -> Result<()> {
+  let config = |currency_code: &str| {
+    format!(
+      r#"{{\"paymentMethodName\": \"Cash on Delivery\", \"cartTotal\": {{\"amount\": \"100.00\", \"currencyCode\": \"{}\"}}}}"#,
+      currency_code
+    )
+  };
+
+  // The cart is in USD
+  assert_eq!(run_with_cart_total("150.00", &config("USD"))?, hidden_cash_on_delivery());
+  assert_eq!(run_with_cart_total("99.99", &config("USD"))?, output::FunctionResult { operations: vec![] });
+  assert_eq!(run_with_cart_total("150.00", &config("EUR"))?, output::FunctionResult { operations: vec![] });
+  assert_eq!(
+    take_logs_without_rule_operations().last().unwrap()["message"],
+    "Cart currency doesn't match the configured currency, no need to hide the payment method."
+  );
+  Ok(())
+}
+
+#[test]
+fn test_money_object_without_a_currency_compares_any_currency() -> Result<()> {
+  let config = r#"{\"paymentMethodName\": \"Cash on Delivery\", \"cartTotal\": {\"amount\": \"100.00\"}}"#;
+
+  assert_eq!(run_with_cart_total("150.00", config)?, hidden_cash_on_delivery());
+  Ok(())
+}
+
+#[test]
+fn test_money_object_currency_must_match_currency_code() {
+  let config = Configuration::from_str(
+    r#"{"paymentMethodName": "Cash", "cartTotal": {"amount": 100, "currencyCode": "USD"}, "currencyCode": "EUR"}"#,
+  )
+  .unwrap();
+
+  assert_eq!(config.validate(), Err(vec!["rules[0]: the cartTotal currency must match currencyCode".to_string()]));
+}
+
+#[test]
+fn test_currency_code_must_match_cart_currency() -> Result<()> {
+  let config = r#"{\"paymentMethodName\": \"Cash on Delivery\", \"cartTotal\": 100, \"currencyCode\": \"USD\"}"#;
+
+  assert_eq!(run_with_cart_total("150.00", config)?, hidden_cash_on_delivery());
+
+  let config = r#"{\"paymentMethodName\": \"Cash on Delivery\", \"cartTotal\": 100, \"currencyCode\": \"JPY\"}"#;
+
+  assert_eq!(
+    run_with_cart_total("150.00", config)?,
+    output::FunctionResult { operations: vec![] }
+  );
+  assert_eq!(
+    take_logs_without_rule_operations(),
+    vec![json!({
+      "level": "info",
+      "message": "Cart currency doesn't match the configured currency, no need to hide the payment method.",
+      "cart_currency": "USD",
+      "currency_code": "JPY"
+    })]
+  );
+  Ok(())
+}
+
+#[test]
+fn test_cart_total_by_currency_uses_currency_specific_threshold() -> Result<()> {
+  let config = r#"{\"paymentMethodName\": \"Cash on Delivery\", \"cartTotal\": 100, \"cartTotalByCurrency\": {\"EUR\": \"90.00\"}}"#;
+
+  assert_eq!(run_with_cart_total_in("95.00", "EUR", config)?, hidden_cash_on_delivery());
+  assert_eq!(
+    run_with_cart_total_in("89.99", "EUR", config)?,
+    output::FunctionResult { operations: vec![] }
+  );
+  Ok(())
+}
+
+#[test]
+fn test_cart_total_by_currency_falls_back_to_default_threshold() -> Result<()> {
+  let config = r#"{\"paymentMethodName\": \"Cash on Delivery\", \"cartTotal\": 100, \"cartTotalByCurrency\": {\"EUR\": 90}}"#;
+
+  assert_eq!(
+    run_with_cart_total_in("95.00", "CHF", config)?,
+    output::FunctionResult { operations: vec![] }
+  );
+  assert_eq!(run_with_cart_total_in("100.00", "CHF", config)?, hidden_cash_on_delivery());
+  Ok(())
+}
+
+#[test]
+fn test_no_threshold_for_currency_returns_no_changes() -> Result<()> {
+  let config = r#"{\"paymentMethodName\": \"Cash on Delivery\", \"cartTotalByCurrency\": {\"EUR\": 90}}"#;
+
+  assert_eq!(
+    run_with_cart_total_in("500.00", "CHF", config)?,
+    output::FunctionResult { operations: vec![] }
+  );
+  Ok(())
+}
+
+#[test]
+fn test_multiple_rules_hide_methods_independently() -> Result<()> {
+  let methods = r#"[
+    { "id": "1", "name": "Cash on Delivery" },
+    { "id": "2", "name": "Cheque" },
+    { "id": "3", "name": "Credit Card" }
+  ]"#;
+  let config = concat!(
+    r#"{\"rules\": ["#,
+    r#"{\"paymentMethodNames\": [\"Cash\"], \"cartTotal\": 100},"#,
+    r#"{\"paymentMethodNames\": [\"Cheque\"], \"cartTotal\": 500}"#,
+    r#"]}"#
+  );
+
+  assert_eq!(hidden_ids(&run_with_payment_methods(methods, config)?), vec!["1"]);
+  Ok(())
+}
+
+#[test]
+fn test_overlapping_rules_do_not_duplicate_hides() -> Result<()> {
+  let methods = r#"[
+    { "id": "1", "name": "Cash on Delivery" },
+    { "id": "2", "name": "Cheque" },
+    { "id": "3", "name": "Credit Card" }
+  ]"#;
+  let config = concat!(
+    r#"{\"rules\": ["#,
+    r#"{\"paymentMethodNames\": [\"Cash\", \"Cheque\"], \"cartTotal\": 100},"#,
+    r#"{\"paymentMethodNames\": [\"Cash on Delivery\"], \"cartTotal\": 50}"#,
+    r#"]}"#
+  );
+  let result = run_with_payment_methods(methods, config)?;
+
+  assert_eq!(hidden_ids(&result), vec!["1", "2"]);
+  assert_eq!(result.operations.len(), 2);
+  Ok(())
+}
+
+#[test]
+fn test_cart_total_gate_logs_structured_context() -> Result<()> {
+  let config = r#"{\"paymentMethodName\": \"Cash on Delivery\", \"cartTotal\": \"100.00\"}"#;
+  run_with_cart_total("99.99", config)?;
+
+  assert_eq!(
+    take_logs(),
+    vec![json!({
+      "level": "info",
+      "message": "Cart total is not high enough, no need to hide the payment method.",
+      "cart_total": "99.99",
+      "threshold": "100.00"
+    })]
+  );
+  Ok(())
+}
+
+fn run_with_lines(lines: &str, config: &str) -> Result<output::FunctionResult> {
+  let mut input = base_input();
+  input["cart"]["lines"] = serde_json::from_str(lines)?;
+  input["paymentMethods"][0]["name"] = serde_json::json!("In-store Pickup");
+  run_with_input(input, config)
+}
+
+// Two 1.5 kg items, a 2 lb item, one variant without weight data and a custom product, about 3.9 kg
+const HEAVY_LINES: &str = r#"[
+  { "quantity": 2, "merchandise": { "__typename": "ProductVariant", "weight": 1.5, "weightUnit": "KILOGRAMS" } },
+  { "quantity": 1, "merchandise": { "__typename": "ProductVariant", "weight": 2.0, "weightUnit": "POUNDS" } },
+  { "quantity": 3, "merchandise": { "__typename": "ProductVariant", "weight": null, "weightUnit": "GRAMS" } },
+  { "quantity": 1, "merchandise": { "__typename": "CustomProduct" } }
+]"#;
+
+#[test]
+fn test_min_cart_weight_hides_method_for_heavy_carts() -> Result<()> {
+  let result = run_with_lines(HEAVY_LINES, r#"{\"paymentMethodName\": \"In-store Pickup\", \"minCartWeightGrams\": 3900}"#)?;
+
+  assert_eq!(hidden_ids(&result), vec!["1"]);
+  Ok(())
+}
+
+#[test]
+fn test_min_cart_weight_keeps_method_for_light_carts() -> Result<()> {
+  let result = run_with_lines(HEAVY_LINES, r#"{\"paymentMethodName\": \"In-store Pickup\", \"minCartWeightGrams\": 4000}"#)?;
+
+  assert_eq!(result, output::FunctionResult { operations: vec![] });
+  let logs = take_logs();
+  assert_eq!(logs[0]["message"], "Cart weight is not high enough, no need to hide the payment method.");
+  Ok(())
+}
+
+#[test]
+fn test_min_cart_weight_combines_with_cart_total() -> Result<()> {
+  let config = r#"{\"paymentMethodName\": \"In-store Pickup\", \"minCartWeightGrams\": 1000, \"cartTotal\": 200}"#;
+
+  assert_eq!(run_with_lines(HEAVY_LINES, config)?, output::FunctionResult { operations: vec![] });
+  Ok(())
+}
+
+fn quantity_lines(quantities: &[i64]) -> String {
+  let lines: Vec<String> = quantities
+    .iter()
+    .map(|quantity| format!(r#"{{ "quantity": {}, "merchandise": {{ "__typename": "CustomProduct" }} }}"#, quantity))
+    .collect();
+  format!("[{}]", lines.join(", "))
+}
+
+#[test]
+fn test_min_total_quantity_sums_quantities_across_lines() -> Result<()> {
+  let config = r#"{\"paymentMethodName\": \"In-store Pickup\", \"minTotalQuantity\": 20}"#;
+
+  assert_eq!(hidden_ids(&run_with_lines(&quantity_lines(&[8, 7, 6]), config)?), vec!["1"]);
+  assert_eq!(
+    run_with_lines(&quantity_lines(&[8, 7, 4]), config)?,
+    output::FunctionResult { operations: vec![] }
+  );
+  Ok(())
+}
+
+#[test]
+fn test_min_total_quantity_hides_exactly_at_threshold() -> Result<()> {
+  let config = r#"{\"paymentMethodName\": \"In-store Pickup\", \"minTotalQuantity\": 20}"#;
+
+  assert_eq!(hidden_ids(&run_with_lines(&quantity_lines(&[15, 5]), config)?), vec!["1"]);
+  Ok(())
+}
+
+fn run_with_buyer_identity(buyer_identity: &str, config: &str) -> Result<output::FunctionResult> {
+  let mut input = base_input();
+  input["cart"]["buyerIdentity"] = serde_json::from_str(buyer_identity)?;
+  run_with_input(input, config)
+}
+
+const WHOLESALE_ONLY: &str =
+  r#"{\"paymentMethodName\": \"Cash on Delivery\", \"cartTotal\": 100, \"requiredCustomerTags\": [\"wholesale\"]}"#;
+
+#[test]
+fn test_required_customer_tags_apply_to_tagged_buyers() -> Result<()> {
+  let result = run_with_buyer_identity(r#"{ "customer": { "wholesale": true } }"#, WHOLESALE_ONLY)?;
+
+  assert_eq!(result, hidden_cash_on_delivery());
+  Ok(())
+}
+
+#[test]
+fn test_required_customer_tags_skip_untagged_buyers() -> Result<()> {
+  let result = run_with_buyer_identity(r#"{ "customer": { "wholesale": false } }"#, WHOLESALE_ONLY)?;
+
+  assert_eq!(result, output::FunctionResult { operations: vec![] });
+  Ok(())
+}
+
+#[test]
+fn test_required_customer_tags_skip_anonymous_buyers() -> Result<()> {
+  for buyer_identity in ["null", r#"{ "customer": null }"#] {
+    let result = run_with_buyer_identity(buyer_identity, WHOLESALE_ONLY)?;
+
+    assert_eq!(result, output::FunctionResult { operations: vec![] });
+  }
+  Ok(())
+}
+
+#[test]
+fn test_required_customer_tags_must_all_match() {
+  // Only `wholesale` is queried, so the configuration is invalid, but `apply` still shows how tags combine
+  let config = Configuration::from_str(
+    r#"{"paymentMethodName": "Cash on Delivery", "cartTotal": 100, "requiredCustomerTags": ["wholesale", "vip"]}"#,
+  ).unwrap();
+  let mut input = input_with("150.00", r#"[{ "id": "1", "name": "Cash on Delivery" }]"#);
+  input.cart.buyer_identity = serde_json::from_value(serde_json::json!({ "customer": { "wholesale": true } })).unwrap();
+
+  assert!(apply(&config, &input).operations.is_empty());
+}
+
+#[test]
+fn test_required_customer_tags_must_be_queried() -> Result<()> {
+  let config = r#"{\"paymentMethodName\": \"Cash\", \"cartTotal\": 0, \"requiredCustomerTags\": [\"vip\"]}"#;
+
+  let result = run_with_buyer_identity(r#"{ "customer": { "wholesale": true } }"#, config)?;
+
+  assert_eq!(result, output::FunctionResult { operations: vec![] });
+  let logs = take_logs();
+  assert_eq!(logs.len(), 1);
+  assert_eq!(
+    logs[0]["errors"],
+    serde_json::json!([r#"rules[0]: requiredCustomerTags "vip" isn't queried, the supported tags are wholesale"#])
+  );
+  Ok(())
+}
+
+#[test]
+fn test_every_supported_customer_tag_is_queried() {
+  let query = include_str!("../input.graphql");
+
+  for (tag, _) in CUSTOMER_TAGS {
+    assert!(query.contains(&format!("{}: hasAnyTag(tags: [\"{}\"])", tag, tag)), "{} isn't queried", tag);
+  }
+}
+
+// Run the function against a recorded Shopify input in `tests/fixtures`
+fn run_fixture(name: &str) -> Result<output::FunctionResult> {
+  let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name);
+  let input = std::fs::read_to_string(&path)
+    .unwrap_or_else(|error| panic!("unable to read fixture {}: {}", path.display(), error));
+  run_function_with_input(function, &input)
+}
+
+#[test]
+fn test_fixture_hides_cash_on_delivery() -> Result<()> {
+  let result = run_fixture("hide_cash_on_delivery.json")?;
+
+  assert_eq!(hidden_ids(&result), vec!["gid://shopify/PaymentCustomizationPaymentMethod/1"]);
+  Ok(())
+}
+
+// Compare the serialized result with `tests/snapshots/<name>.snap`. Run the tests with
+// `UPDATE_SNAPSHOTS=1` to write the current result as the new snapshot.
+fn assert_snapshot(name: &str, result: &output::FunctionResult) {
+  let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+    .join("tests/snapshots")
+    .join(format!("{}.snap", name));
+  let actual = serde_json::to_string_pretty(result).expect("results should serialize") + "\n";
+  if std::env::var_os("UPDATE_SNAPSHOTS").is_some() {
+    std::fs::create_dir_all(path.parent().unwrap()).expect("unable to create the snapshots directory");
+    std::fs::write(&path, &actual).expect("unable to write the snapshot");
+    return;
+  }
+  let expected = std::fs::read_to_string(&path).unwrap_or_else(|error| {
+    panic!("unable to read snapshot {} ({}), run with UPDATE_SNAPSHOTS=1 to create it", path.display(), error)
+  });
+  assert_eq!(actual, expected, "result doesn't match snapshot {}", path.display());
+}
+
+#[test]
+fn test_fixture_snapshot() -> Result<()> {
+  let result = run_fixture("hide_cash_on_delivery.json")?;
+
+  assert_snapshot("hide_cash_on_delivery", &result);
+  Ok(())
+}
+
+fn run_with_cart_cost(subtotal: &str, total: &str, config: &str) -> Result<output::FunctionResult> {
+  let mut input = base_input();
+  input["cart"]["cost"]["subtotalAmount"]["amount"] = serde_json::json!(subtotal);
+  input["cart"]["cost"]["totalAmount"]["amount"] = serde_json::json!(total);
+  run_with_input(input, config)
+}
+
+#[test]
+fn test_min_cart_total_pct_of_subtotal_boundary() -> Result<()> {
+  let config = r#"{\"paymentMethodName\": \"Cash on Delivery\", \"minCartTotalPctOfSubtotal\": 110.0}"#;
+
+  assert_eq!(run_with_cart_cost("100.00", "110.00", config)?, hidden_cash_on_delivery());
+  assert_eq!(run_with_cart_cost("100.00", "109.99", config)?, output::FunctionResult { operations: vec![] });
+  Ok(())
+}
+
+#[test]
+fn test_min_cart_total_pct_of_subtotal_ignores_zero_subtotal() -> Result<()> {
+  let config = r#"{\"paymentMethodName\": \"Cash on Delivery\", \"minCartTotalPctOfSubtotal\": 50.0}"#;
+
+  assert_eq!(run_with_cart_cost("0.00", "25.00", config)?, output::FunctionResult { operations: vec![] });
+  Ok(())
+}
+
+const CARD_METHODS: &str = r#"[
+  { "id": "1", "name": "Carte Bancaire" },
+  { "id": "2", "name": "Credit Card (Visa/MC)" },
+  { "id": "3", "name": "Cash on Delivery" }
+]"#;
+
+#[test]
+fn test_name_regex_matches_several_methods() -> Result<()> {
+  let config = r#"{\"nameRegex\": \"^(carte|credit card)\", \"cartTotal\": 100}"#;
+
+  assert_eq!(hidden_ids(&run_with_payment_methods(CARD_METHODS, config)?), vec!["1", "2"]);
+  Ok(())
+}
+
+#[test]
+fn test_name_regex_combines_with_configured_names() -> Result<()> {
+  let config = r#"{\"paymentMethodName\": \"Cash\", \"nameRegex\": \"visa\", \"cartTotal\": 100}"#;
+
+  assert_eq!(hidden_ids(&run_with_payment_methods(CARD_METHODS, config)?), vec!["2", "3"]);
+  Ok(())
+}
+
+#[test]
+fn test_invalid_name_regex_returns_no_changes_and_logs() -> Result<()> {
+  let config = r#"{\"nameRegex\": \"(carte\", \"cartTotal\": 100}"#;
+
+  let result = run_with_payment_methods(CARD_METHODS, config)?;
+
+  assert_eq!(result, output::FunctionResult { operations: vec![] });
+  let logs = take_logs();
+  assert_eq!(logs.len(), 1);
+  assert_eq!(logs[0]["level"], "error");
+  assert_eq!(logs[0]["message"], "Invalid configuration value in metafield");
+  assert!(logs[0]["errors"][0].as_str().unwrap().starts_with("rules[0]: nameRegex is invalid"));
+  Ok(())
+}
+
+#[test]
+fn test_apply_returns_no_changes_for_invalid_name_regex() {
+  let config = Configuration::from_str(r#"{"nameRegex": "(carte", "cartTotal": 100}"#).unwrap();
+
+  assert!(apply(&config, &input_with("150.00", CARD_METHODS)).operations.is_empty());
+  let logs = take_logs();
+  assert_eq!(logs[0]["message"], "Unable to compile the payment method name regex");
+  assert_eq!(logs[0]["name_regex"], "(carte");
+}
+
+#[test]
+fn test_v1_configuration_is_migrated_to_current_version() {
+  let v1 = Configuration::from_str(r#"{"paymentMethodName": "Cash on Delivery", "cartTotal": 100}"#).unwrap();
+  let v2 = Configuration::from_str(
+    r#"{"version": 2, "rules": [{"paymentMethodNames": ["Cash on Delivery"], "cartTotal": 100}]}"#,
+  ).unwrap();
+
+  assert_eq!(v1.version, CONFIGURATION_VERSION);
+  assert_eq!(v1.rules.len(), 1);
+  assert_eq!(v1.rules[0].payment_method_names, vec!["Cash on Delivery"]);
+  assert!(v1 == v2);
+}
+
+#[test]
+fn test_unsupported_configuration_version_falls_back_to_defaults() -> Result<()> {
+  let config = r#"{\"version\": 3, \"paymentMethodName\": \"Cash on Delivery\", \"cartTotal\": 100}"#;
+
+  assert_eq!(run_with_cart_total("150.00", config)?, output::FunctionResult { operations: vec![] });
+  let logs = take_logs();
+  assert_eq!(logs.len(), 1);
+  assert_eq!(logs[0]["level"], "warn");
+  assert_eq!(logs[0]["version"], 3);
+  Ok(())
+}
+
+const FIVE_METHODS: &str = r#"[
+  { "id": "1", "name": "Credit Card" },
+  { "id": "2", "name": "Shop Pay" },
+  { "id": "3", "name": "Cash on Delivery" },
+  { "id": "4", "name": "Bank Deposit" },
+  { "id": "5", "name": "Money Order" }
+]"#;
+
+#[test]
+fn test_allowed_payment_method_names_hide_every_other_method() -> Result<()> {
+  let config = r#"{\"allowedPaymentMethodNames\": [\"credit card\", \"Shop Pay\"], \"cartTotal\": 100}"#;
+
+  assert_eq!(hidden_ids(&run_with_payment_methods(FIVE_METHODS, config)?), vec!["3", "4", "5"]);
+  Ok(())
+}
+
+#[test]
+fn test_empty_allowed_payment_method_names_hide_nothing() -> Result<()> {
+  let config = r#"{\"allowedPaymentMethodNames\": [], \"cartTotal\": 100}"#;
+
+  let result = run_with_payment_methods(FIVE_METHODS, config)?;
+
+  assert_eq!(result, output::FunctionResult { operations: vec![] });
+  let logs = take_logs();
+  assert_eq!(logs.len(), 1);
+  assert_eq!(logs[0]["level"], "warn");
+  Ok(())
+}
+
+const GATEWAY_SPELLED_METHODS: &str = r#"[
+  { "id": "1", "name": "Carte-Bancaire" },
+  { "id": "2", "name": "Paiement à la livraison" },
+  { "id": "3", "name": "PayPal" }
+]"#;
+
+#[test]
+fn test_normalized_names_match_regardless_of_accents_and_punctuation() -> Result<()> {
+  let config = r#"{\"normalizeNames\": true, \"paymentMethodNames\": [\"carte bancaire\", \"Paiement a la livraison!\"], \"cartTotal\": 100}"#;
+
+  assert_eq!(hidden_ids(&run_with_payment_methods(GATEWAY_SPELLED_METHODS, config)?), vec!["1", "2"]);
+  Ok(())
+}
+
+#[test]
+fn test_names_only_match_exactly_as_written_without_normalize_names() -> Result<()> {
+  let config = r#"{\"paymentMethodNames\": [\"carte bancaire\", \"Paiement a la livraison!\"], \"cartTotal\": 100}"#;
+
+  assert!(run_with_payment_methods(GATEWAY_SPELLED_METHODS, config)?.operations.is_empty());
+  Ok(())
+}
+
+#[test]
+fn test_normalized_allowed_names_keep_differently_spelled_methods() -> Result<()> {
+  let config = r#"{\"normalizeNames\": true, \"allowedPaymentMethodNames\": [\"Carte Bancaire\"], \"cartTotal\": 100}"#;
+
+  assert_eq!(hidden_ids(&run_with_payment_methods(GATEWAY_SPELLED_METHODS, config)?), vec!["2", "3"]);
+  Ok(())
+}
+
+#[test]
+fn test_names_without_letters_or_digits_are_invalid_with_normalize_names() {
+  let config = Configuration::from_str(r#"{"normalizeNames": true, "paymentMethodNames": ["Cash", "--"], "cartTotal": 100}"#).unwrap();
+
+  assert_eq!(
+    config.validate(),
+    Err(vec!["rules[0]: names must have letters or digits when normalizeNames is set".to_string()])
+  );
+}
+
+#[test]
+fn test_dry_run_logs_operations_without_applying_them() -> Result<()> {
+  let config = r#"{\"paymentMethodName\": \"Cash on Delivery\", \"cartTotal\": 100, \"dryRun\": true}"#;
+
+  let result = run_with_cart_total("150.00", config)?;
+
+  assert_eq!(result, output::FunctionResult { operations: vec![] });
+  let logs = take_logs_without_rule_operations();
+  assert_eq!(logs.len(), 1);
+  assert_eq!(logs[0]["message"], "Dry run, not customizing payment methods.");
+  assert_eq!(logs[0]["operations"], serde_json::json!([{ "hide": { "paymentMethodId": "1" } }]));
+  Ok(())
+}
+
+// A payment input for calling `apply` directly, with the payment methods from `run_with_payment_methods`
+fn input_with(cart_total: &str, methods: &str) -> input::ResponseData {
+  let mut input = base_input();
+  input["cart"]["cost"]["subtotalAmount"]["amount"] = serde_json::json!(cart_total);
+  input["cart"]["cost"]["totalAmount"]["amount"] = serde_json::json!(cart_total);
+  input["paymentMethods"] = serde_json::from_str(methods).expect("methods should be JSON");
+  serde_json::from_value(input).expect("the input should match the generated types")
+}
+
+#[test]
+fn test_apply_hides_methods_above_cart_total() {
+  let config = Configuration::from_str(r#"{"paymentMethodName": "Cash", "cartTotal": 100}"#).unwrap();
+
+  assert_eq!(hidden_ids(&apply(&config, &input_with("100.00", FIVE_METHODS))), vec!["3"]);
+  assert!(apply(&config, &input_with("99.99", FIVE_METHODS)).operations.is_empty());
+}
+
+#[test]
+fn test_apply_renames_and_moves_per_rule() {
+  let config = Configuration::from_str(
+    r#"{"rules": [{"paymentMethodName": "Shop Pay", "cartTotal": 0, "moveToIndex": 0}, {"paymentMethodName": "Bank", "cartTotal": 0, "renameTo": "Wire Transfer"}]}"#,
+  ).unwrap();
+
+  let result = apply(&config, &input_with("10.00", FIVE_METHODS));
+
+  assert_eq!(result.operations.len(), 2);
+  assert_eq!(result.operations[0].rename.as_ref().map(|rename| rename.name.as_str()), Some("Wire Transfer"));
+  assert_eq!(result.operations[1].move_.as_ref().map(|move_| move_.payment_method_id.as_str()), Some("2"));
+}
+
+#[test]
+fn test_operations_are_in_the_same_order_whatever_the_payment_method_order() {
+  let config = Configuration::from_str(
+    r#"{"rules": [{"paymentMethodName": "Money Order", "cartTotal": 0}, {"paymentMethodName": "Bank", "cartTotal": 0, "renameTo": "Wire Transfer"}, {"paymentMethodName": "Cash", "cartTotal": 0}]}"#,
+  ).unwrap();
+  let mut shuffled: Vec<serde_json::Value> = serde_json::from_str(FIVE_METHODS).unwrap();
+  shuffled.reverse();
+  shuffled.swap(0, 2);
+
+  let result = apply(&config, &input_with("10.00", FIVE_METHODS));
+
+  assert_eq!(result.operations[0].rename.as_ref().map(|rename| rename.payment_method_id.as_str()), Some("4"));
+  assert_eq!(hidden_ids(&result), vec!["3", "5"]);
+  assert_eq!(apply(&config, &input_with("10.00", &serde_json::to_string(&shuffled).unwrap())), result);
+}
+
+#[test]
+fn test_apply_returns_no_changes_for_unparseable_cart_total() {
+  let config = Configuration::from_str(r#"{"paymentMethodName": "Cash", "cartTotal": 100}"#).unwrap();
+
+  assert!(apply(&config, &input_with("lots", FIVE_METHODS)).operations.is_empty());
+}
+
+#[test]
+fn test_missing_metafield_returns_no_changes_and_logs_the_field() -> Result<()> {
+  let result = run_function_with_input(
+    function,
+    r#"
+      {
+        "cart": {
+          "deliveryGroups": [],
+          "lines": [],
+          "cost": {
+            "subtotalAmount": { "amount": "150.0" },
+            "totalAmount": { "amount": "150.0", "currencyCode": "USD" }
+          }
+        },
+        "paymentMethods": [{ "id": "1", "name": "Cash on Delivery" }],
+        "paymentCustomization": {
+          "metafield": null
+        }
+      }
+    "#,
+  )?;
+
+  assert_eq!(result, output::FunctionResult { operations: vec![] });
+  let logs = take_logs();
+  assert_eq!(logs.len(), 1);
+  assert_eq!(logs[0]["level"], "info");
+  assert_eq!(logs[0]["field"], "paymentCustomization.metafield");
+  Ok(())
+}
+
+#[test]
+fn test_safe_config_returns_none_for_unusable_metafields() {
+  let metafield = |value: &str| Some(input::InputPaymentCustomizationMetafield { value: value.to_string() });
+
+  for metafield in [None, metafield(""), metafield("[]")] {
+    let mut input = input_with("150.00", FIVE_METHODS);
+    input.payment_customization.metafield = metafield;
+
+    assert!(safe_config(&input).is_none());
+  }
+}
+
+#[test]
+fn test_move_to_targets_resolve_against_payment_methods() -> Result<()> {
+  let config = |target: &str| {
+    format!(r#"{{\"paymentMethodName\": \"Cash on Delivery\", \"cartTotal\": 100, \"moveTo\": {}}}"#, target)
+  };
+  let moved_index = |result: output::FunctionResult| result.operations[0].move_.as_ref().map(|move_| move_.index);
+
+  let top = run_with_payment_methods(FIVE_METHODS, &config(r#"\"top\""#))?;
+  let bottom = run_with_payment_methods(FIVE_METHODS, &config(r#"\"bottom\""#))?;
+  let index = run_with_payment_methods(FIVE_METHODS, &config(r#"{\"index\": 1}"#))?;
+
+  assert_eq!(moved_index(top), Some(0));
+  assert_eq!(moved_index(bottom), Some(4));
+  assert_eq!(moved_index(index), Some(1));
+  Ok(())
+}
+
+#[test]
+fn test_validate_collects_every_error() {
+  let config = Configuration::from_str(concat!(
+    r#"{"rules": [{"paymentMethodName": "Cash", "cartTotal": 100, "cartTotalMax": 50}, "#,
+    r#"{"paymentMethodName": "Cash", "cartTotal": -1, "minTotalQuantity": -2, "renameTo": ""}]}"#
+  )).unwrap();
+
+  assert_eq!(
+    config.validate(),
+    Err(vec![
+      "rules[0]: cartTotalMax must not be below cartTotal".to_string(),
+      "rules[1]: cart totals must not be negative".to_string(),
+      "rules[1]: minTotalQuantity must not be negative".to_string(),
+      "rules[1]: renameTo must not be empty".to_string(),
+    ])
+  );
+}
+
+#[test]
+fn test_doubly_encoded_configuration_parses_the_same() {
+  let value = r#"{"paymentMethodName": "Cash on Delivery", "cartTotal": "100.00"}"#;
+  let encoded = serde_json::to_string(value).unwrap();
+
+  assert!(Configuration::from_str(&encoded).unwrap() == Configuration::from_str(value).unwrap());
+}
+
+#[test]
+fn test_input_query_targets_the_configured_metafield() {
+  let namespace = option_env!("PAYMENT_CONFIG_METAFIELD_NAMESPACE").unwrap_or("$app:payment-customization");
+  let key = option_env!("PAYMENT_CONFIG_METAFIELD_KEY").unwrap_or("function-configuration");
+
+  assert!(INPUT_QUERY.contains(&format!("namespace: \"{}\"", namespace)));
+  assert!(INPUT_QUERY.contains(&format!("key: \"{}\"", key)));
+}
+
+#[test]
+fn test_building_leaves_the_tracked_input_query_as_it_is() {
+  let query = include_str!("../input.graphql");
+
+  assert!(query.contains("namespace: \"$app:payment-customization\""));
+  assert!(query.contains("key: \"function-configuration\""));
+}
+
+#[test]
+fn test_names_match_after_whitespace_normalization() {
+  let methods = r#"[{ "id": "1", "name": "Cash on  Delivery " }, { "id": "2", "name": "Bank Deposit" }]"#;
+  let config = Configuration::from_str(r#"{"paymentMethodNames": [" Cash on Delivery\n"], "cartTotal": 0}"#).unwrap();
+  let allow_list = Configuration::from_str(r#"{"allowedPaymentMethodNames": ["  Bank\tDeposit "], "cartTotal": 0}"#).unwrap();
+
+  assert_eq!(hidden_ids(&apply(&config, &input_with("10.00", methods))), vec!["1"]);
+  assert_eq!(hidden_ids(&apply(&allow_list, &input_with("10.00", methods))), vec!["1"]);
+}
+
+#[test]
+fn test_hiding_every_method_keeps_the_first_visible() {
+  let methods = r#"[{ "id": "1", "name": "Cash" }, { "id": "2", "name": "Cash on Delivery" }]"#;
+  let config = Configuration::from_str(r#"{"paymentMethodName": "Cash", "cartTotal": 0}"#).unwrap();
+  take_logs_without_rule_operations();
+
+  assert_eq!(hidden_ids(&apply(&config, &input_with("10.00", methods))), vec!["2"]);
+  let logs = take_logs_without_rule_operations();
+  assert_eq!(logs[0]["level"], "warn");
+  assert_eq!(logs[0]["payment_method_id"], "1");
+}
+
+fn rounding_config(threshold: &str, rounding: &str) -> String {
+  format!(
+    r#"{{\"paymentMethodName\": \"Cash on Delivery\", \"cartTotal\": \"{}\", \"rounding\": \"{}\"}}"#,
+    threshold, rounding
+  )
+}
+
+#[test]
+fn test_rounding_modes_at_a_threshold_boundary() -> Result<()> {
+  let no_changes = output::FunctionResult { operations: vec![] };
+
+  // 99.985 rounds up to 99.99 only when midpoints round away from zero
+  assert_eq!(run_with_cart_total("99.985", &rounding_config("99.99", "halfUp"))?, hidden_cash_on_delivery());
+  assert_eq!(run_with_cart_total("99.985", &rounding_config("99.99", "halfEven"))?, no_changes);
+  assert_eq!(run_with_cart_total("99.985", &rounding_config("99.99", "down"))?, no_changes);
+
+  // 99.995 rounds up to 100.00 at the nearest even digit, but is cut to 99.99 when rounding down
+  assert_eq!(run_with_cart_total("99.995", &rounding_config("100.00", "halfEven"))?, hidden_cash_on_delivery());
+  assert_eq!(run_with_cart_total("99.995", &rounding_config("100.00", "down"))?, no_changes);
+  Ok(())
+}
+
+#[test]
+fn test_rounding_uses_the_currency_decimal_places() -> Result<()> {
+  let no_changes = output::FunctionResult { operations: vec![] };
+
+  // Kuwaiti dinars have three decimal places, so 99.9985 rounds to 99.998 at the nearest even digit
+  // and only reaches 99.999 when midpoints round away from zero
+  assert_eq!(run_with_cart_total_in("99.9985", "KWD", &rounding_config("99.999", "halfEven"))?, no_changes);
+  assert_eq!(
+    run_with_cart_total_in("99.9985", "KWD", &rounding_config("99.999", "halfUp"))?,
+    hidden_cash_on_delivery()
+  );
+  Ok(())
+}
+
+// Builds a configuration rule by rule for tests, filling in what `Configuration::from_str` would
+struct ConfigurationBuilder {
+  config: Configuration,
+  rule: PaymentRule
+}
+
+impl ConfigurationBuilder {
+  fn new() -> Self {
+    ConfigurationBuilder { config: Configuration::default(), rule: ConfigurationBuilder::default_rule() }
+  }
+
+  // A rule with the defaults serde fills in
+  fn default_rule() -> PaymentRule {
+    PaymentRule { case_insensitive: true, ..PaymentRule::default() }
+  }
+
+  fn payment_method_name(mut self, name: &str) -> Self {
+    self.rule.payment_method_names.push(name.to_string());
+    self
+  }
+
+  fn cart_total(mut self, cart_total: &str) -> Self {
+    self.rule.cart_total = money::parse_decimal(cart_total).map(MoneyInput::from);
+    self
+  }
+
+  fn rename_to(mut self, name: &str) -> Self {
+    self.rule.rename_to = Some(name.to_string());
+    self
+  }
+
+  fn move_to(mut self, target: MoveTarget) -> Self {
+    self.rule.move_to = Some(target);
+    self
+  }
+
+  fn dry_run(mut self, dry_run: bool) -> Self {
+    self.config.dry_run = dry_run;
+    self
+  }
+
+  // Finish the current rule and start another one
+  fn next_rule(mut self) -> Self {
+    let rule = std::mem::replace(&mut self.rule, ConfigurationBuilder::default_rule());
+    self.config.rules.push(rule);
+    self
+  }
+
+  fn build(self) -> Configuration {
+    let mut config = self.next_rule().config;
+    config.version = CONFIGURATION_VERSION;
+    config.prepare();
+    config
+  }
+}
+
+#[test]
+fn test_configuration_builder_matches_parsed_configuration() {
+  let built = ConfigurationBuilder::new()
+    .payment_method_name("Cash on Delivery")
+    .cart_total("100.00")
+    .next_rule()
+    .payment_method_name("Bank Deposit")
+    .cart_total("0")
+    .rename_to("Wire Transfer")
+    .next_rule()
+    .payment_method_name("Shop Pay")
+    .cart_total("0")
+    .move_to(MoveTarget::Top)
+    .dry_run(false)
+    .build();
+  let parsed = Configuration::from_str(
+    r#"{"rules": [{"paymentMethodNames": ["Cash on Delivery"], "cartTotal": "100.00"}, {"paymentMethodNames": ["Bank Deposit"], "cartTotal": "0", "renameTo": "Wire Transfer"}, {"paymentMethodNames": ["Shop Pay"], "cartTotal": "0", "moveTo": "top"}]}"#,
+  ).unwrap();
+
+  assert!(built == parsed);
+  assert_eq!(built.validate(), Ok(()));
+  assert_eq!(hidden_ids(&apply(&built, &input_with("100.00", FIVE_METHODS))).len(), 1);
+}
+
+const DUPLICATE_NAME_METHODS: &str = r#"[
+  { "id": "1", "name": "Bank Transfer" },
+  { "id": "2", "name": "Credit Card" },
+  { "id": "3", "name": "Bank Transfer" }
+]"#;
+
+#[test]
+fn test_same_named_methods_are_all_hidden_by_default() {
+  let config = Configuration::from_str(r#"{"paymentMethodName": "Bank Transfer", "cartTotal": 0}"#).unwrap();
+
+  assert_eq!(hidden_ids(&apply(&config, &input_with("10.00", DUPLICATE_NAME_METHODS))), vec!["1", "3"]);
+}
+
+#[test]
+fn test_first_match_only_hides_one_of_the_same_named_methods() {
+  let config = Configuration::from_str(
+    r#"{"paymentMethodName": "Bank Transfer", "cartTotal": 0, "firstMatchOnly": true}"#,
+  ).unwrap();
+
+  assert_eq!(hidden_ids(&apply(&config, &input_with("10.00", DUPLICATE_NAME_METHODS))), vec!["1"]);
+}
+
+#[test]
+fn test_operation_constructors_set_exactly_one_action() {
+  let id = || "1".to_string();
+  let hide = output::Operation::hide(output::HideOperation { payment_method_id: id() });
+  let move_ = output::Operation::move_to(output::MoveOperation { payment_method_id: id(), index: 1 });
+  let rename = output::Operation::rename(output::RenameOperation { payment_method_id: id(), name: "Card".to_string() });
+
+  assert!(hide.hide.is_some() && hide.move_.is_none() && hide.rename.is_none());
+  assert!(move_.hide.is_none() && move_.move_.is_some() && move_.rename.is_none());
+  assert!(rename.hide.is_none() && rename.move_.is_none() && rename.rename.is_some());
+}
+
+#[test]
+fn test_config_override_supersedes_the_metafield() -> Result<()> {
+  let methods = r#"[{ "id": "1", "name": "Cash on Delivery" }, { "id": "2", "name": "Credit Card" }]"#;
+  let config = r#"{\"paymentMethodName\": \"Cash on Delivery\", \"cartTotal\": 100}"#;
+  std::env::set_var(CONFIG_OVERRIDE_VARIABLE, r#"{"paymentMethodName": "Credit Card", "cartTotal": 100}"#);
+
+  let ignored = run_with_payment_methods(methods, config)?;
+  READ_CONFIG_OVERRIDE.with(|read| read.set(true));
+  let overridden = run_with_payment_methods(methods, config)?;
+  READ_CONFIG_OVERRIDE.with(|read| read.set(false));
+  std::env::remove_var(CONFIG_OVERRIDE_VARIABLE);
+
+  assert_eq!(hidden_ids(&ignored), vec!["1"]);
+  assert_eq!(hidden_ids(&overridden), vec!["2"]);
+  assert_eq!(take_logs_without_rule_operations()[0]["message"], "Using the configuration from the environment instead of the metafield.");
+  Ok(())
+}
+
+#[test]
+fn test_disabled_configuration_returns_no_changes_and_logs() -> Result<()> {
+  let result = run_with_cart_total("150.00", r#"{\"enabled\": false, \"paymentMethodName\": \"Cash on Delivery\", \"cartTotal\": 100}"#)?;
+
+  assert_eq!(result, output::FunctionResult { operations: vec![] });
+  assert_eq!(
+    take_logs(),
+    vec![json!({ "level": "info", "message": "Customization is disabled, no need to customize payment methods." })]
+  );
+  Ok(())
+}
+
+#[test]
+fn test_enabled_configuration_customizes_as_usual() -> Result<()> {
+  let result = run_with_cart_total("150.00", r#"{\"enabled\": true, \"paymentMethodName\": \"Cash on Delivery\", \"cartTotal\": 100}"#)?;
+
+  assert_eq!(result, hidden_cash_on_delivery());
+  Ok(())
+}
+
+fn run_with_shipping_country(country_code: Option<&str>, config: &str) -> Result<output::FunctionResult> {
+  let mut input = base_input();
+  input["cart"]["deliveryGroups"] = match country_code {
+    Some(country_code) => serde_json::json!([{ "deliveryAddress": { "countryCode": country_code } }]),
+    None => serde_json::json!([{ "deliveryAddress": null }])
+  };
+  run_with_input(input, config)
+}
+
+#[test]
+fn test_hide_for_countries_only_hides_for_listed_countries() -> Result<()> {
+  let config = r#"{\"paymentMethodName\": \"Cash on Delivery\", \"hideForCountries\": [\"fr\", \"BE\"]}"#;
+
+  assert_eq!(run_with_shipping_country(Some("FR"), config)?, hidden_cash_on_delivery());
+  assert_eq!(run_with_shipping_country(Some("US"), config)?, output::FunctionResult { operations: vec![] });
+  Ok(())
+}
+
+#[test]
+fn test_allow_for_countries_hides_outside_listed_countries() -> Result<()> {
+  let config = r#"{\"paymentMethodName\": \"Cash on Delivery\", \"allowForCountries\": \"US\"}"#;
+
+  assert_eq!(run_with_shipping_country(Some("US"), config)?, output::FunctionResult { operations: vec![] });
+  assert_eq!(run_with_shipping_country(Some("CA"), config)?, hidden_cash_on_delivery());
+  Ok(())
+}
+
+#[test]
+fn test_hide_for_countries_takes_precedence_over_allow_for_countries() -> Result<()> {
+  let config = r#"{\"paymentMethodName\": \"Cash on Delivery\", \"allowForCountries\": [\"US\", \"PR\"], \"hideForCountries\": \"PR\"}"#;
+
+  assert_eq!(run_with_shipping_country(Some("US"), config)?, output::FunctionResult { operations: vec![] });
+  assert_eq!(run_with_shipping_country(Some("PR"), config)?, hidden_cash_on_delivery());
+  assert_eq!(run_with_shipping_country(Some("CA"), config)?, hidden_cash_on_delivery());
+  Ok(())
+}
+
+#[test]
+fn test_country_rules_skip_carts_without_a_shipping_country() -> Result<()> {
+  let config = r#"{\"paymentMethodName\": \"Cash on Delivery\", \"allowForCountries\": \"US\"}"#;
+
+  assert_eq!(run_with_shipping_country(None, config)?, output::FunctionResult { operations: vec![] });
+  assert_eq!(take_logs()[0]["message"], "Cart doesn't ship to a targeted country, no need to hide the payment method.");
+  Ok(())
+}
+
+#[test]
+fn test_unsupported_operations_are_dropped_and_logged() -> Result<()> {
+  let result = run_with_payment_methods(
+    r#"[{ "id": "1", "name": "Cash on Delivery" }, { "id": "2", "name": "Shop Pay" }]"#,
+    r#"{\"supportedOperations\": [\"hide\"], \"rules\": [{\"paymentMethodName\": \"Cash on Delivery\", \"cartTotal\": 100}, {\"paymentMethodName\": \"Shop Pay\", \"cartTotal\": 0, \"moveTo\": \"top\"}]}"#,
+  )?;
+
+  assert_eq!(result, hidden_cash_on_delivery());
+  assert_eq!(
+    take_logs_without_rule_operations(),
+    vec![json!({
+      "level": "warn",
+      "message": "Operation is not supported, dropping it.",
+      "kind": "move",
+      "payment_method_id": "2"
+    })]
+  );
+  Ok(())
+}
+
+#[test]
+fn test_profiling_logs_phase_durations_only_when_enabled() -> Result<()> {
+  run_with_cart_total("150.00", r#"{\"paymentMethodName\": \"Cash on Delivery\", \"cartTotal\": 100}"#)?;
+  assert!(take_logs_without_rule_operations().is_empty());
+
+  let result = run_with_cart_total("150.00", r#"{\"paymentMethodName\": \"Cash on Delivery\", \"cartTotal\": 100, \"profile\": true}"#)?;
+
+  assert_eq!(result, hidden_cash_on_delivery());
+  let logs = take_logs_without_rule_operations();
+  assert_eq!(logs.len(), 1);
+  assert_eq!(logs[0]["message"], "Profiled the run.");
+  for field in ["parse_us", "filter_us", "build_us"] {
+    assert!(logs[0][field].is_u64(), "{} should be a duration", field);
+  }
+  Ok(())
+}
+
+#[test]
+fn test_strict_configuration_rejects_unknown_fields() -> Result<()> {
+  let result = run_with_cart_total("150.00", r#"{\"strict\": true, \"paymentMethodName\": \"Cash on Delivery\", \"cartTotl\": 100}"#)?;
+
+  assert_eq!(result, output::FunctionResult { operations: vec![] });
+  let logs = take_logs();
+  assert_eq!(logs[0]["message"], "Unable to parse configuration value from metafield");
+  assert!(logs[0]["error"].as_str().unwrap().contains("unknown fields in strict configuration: cartTotl"));
+  Ok(())
+}
+
+#[test]
+fn test_lenient_configuration_ignores_unknown_fields() -> Result<()> {
+  let result = run_with_cart_total("150.00", r#"{\"paymentMethodName\": \"Cash on Delivery\", \"cartTotal\": 100, \"cartTotl\": 500}"#)?;
+
+  assert_eq!(result, hidden_cash_on_delivery());
+  Ok(())
+}
+
+#[test]
+fn test_strict_configuration_accepts_known_fields() {
+  let config = Configuration::from_str(concat!(
+    r#"{"version": 2, "strict": true, "dryRun": false, "rounding": "halfUp", "rules": [{"paymentMethodNames": ["Cash"], "#,
+    r#""cartTotal": 100, "cartTotalByCurrency": {"EUR": 90}, "minTotalQuantity": 2, "comparison": "lessThan"}]}"#
+  ));
+
+  assert!(config.is_ok());
+}
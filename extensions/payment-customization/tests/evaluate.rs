@@ -0,0 +1,56 @@
+//! The `payment-evaluate` tool runs a configuration file against an input file without deploying.
+
+use std::path::{Path, PathBuf};
+
+use assert_cmd::Command;
+use predicates::prelude::*;
+
+fn evaluate() -> Command {
+  Command::cargo_bin("payment-evaluate").expect("payment-evaluate should be built")
+}
+
+fn fixture(name: &str) -> PathBuf {
+  Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)
+}
+
+// Whether the output is the JSON `expected`, however it's formatted
+fn json_eq(expected: serde_json::Value) -> impl Predicate<[u8]> {
+  predicate::function(move |output: &[u8]| serde_json::from_slice::<serde_json::Value>(output).ok().as_ref() == Some(&expected))
+}
+
+#[test]
+fn test_evaluate_prints_the_result_for_the_configuration_file() {
+  // The configuration file replaces the configuration in the input's metafield
+  evaluate()
+    .arg(fixture("hide_bank_deposit.config.json"))
+    .arg(fixture("hide_cash_on_delivery.json"))
+    .assert()
+    .success()
+    .stdout(json_eq(serde_json::json!({
+      "operations": [{ "hide": { "paymentMethodId": "gid://shopify/PaymentCustomizationPaymentMethod/2" } }]
+    })));
+}
+
+#[test]
+fn test_evaluate_reads_stdin_and_fails_for_an_unusable_configuration() {
+  evaluate()
+    .arg("-")
+    .arg(fixture("hide_cash_on_delivery.json"))
+    .write_stdin(r#"{"paymentMethodName": 1}"#)
+    .assert()
+    .failure()
+    .stdout(predicate::str::is_empty())
+    .stderr(predicate::str::contains("Unable to parse configuration value from metafield"));
+}
+
+#[test]
+fn test_input_query_prints_the_query_built_for_the_configured_metafield() {
+  let namespace = option_env!("PAYMENT_CONFIG_METAFIELD_NAMESPACE").unwrap_or("$app:payment-customization");
+
+  evaluate()
+    .arg("--input-query")
+    .assert()
+    .success()
+    .stdout(predicate::str::starts_with("query Input"))
+    .stdout(predicate::str::contains(format!("namespace: \"{}\"", namespace)));
+}